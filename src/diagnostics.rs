@@ -0,0 +1,81 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Diagnostics about the telemetry the exporter had to modify to fit Application Insights limits.
+///
+/// Create an instance and pass a clone of it to the pipeline or exporter. The counters are shared
+/// between all clones, so you can inspect them while the exporter is running.
+///
+/// ```no_run
+/// let diagnostics = opentelemetry_application_insights::Diagnostics::new();
+/// let tracer = opentelemetry_application_insights::new_pipeline_from_env()
+///     .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
+///     .with_client(reqwest::blocking::Client::new())
+///     .with_diagnostics(diagnostics.clone())
+///     .install_simple();
+///
+/// // ... send traces ...
+///
+/// for (field, count) in diagnostics.truncated_fields() {
+///     println!("{} was truncated {} times", field, count);
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    inner: Arc<Mutex<DiagnosticsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct DiagnosticsInner {
+    truncated_fields: BTreeMap<&'static str, usize>,
+    #[cfg(feature = "metrics")]
+    collapsed_metric_series: BTreeMap<String, usize>,
+}
+
+impl Diagnostics {
+    /// Create new diagnostics with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of values truncated to the Application Insights length limits, per field.
+    ///
+    /// Fields are named after the Application Insights data model, e.g. `"MessageData.message"` or
+    /// `"ai.operation.name"`.
+    pub fn truncated_fields(&self) -> BTreeMap<&'static str, usize> {
+        self.inner.lock().unwrap().truncated_fields.clone()
+    }
+
+    pub(crate) fn record_truncated_field(&self, field: &'static str) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .truncated_fields
+            .entry(field)
+            .or_default() += 1;
+    }
+
+    /// Number of metric data points, per metric name, whose dimension values were replaced with
+    /// `Other` because the metric exceeded the series limit (see
+    /// `Exporter::with_metric_series_limit`).
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn collapsed_metric_series(&self) -> BTreeMap<String, usize> {
+        self.inner.lock().unwrap().collapsed_metric_series.clone()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn record_collapsed_metric_series(&self, metric_name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(count) = inner.collapsed_metric_series.get_mut(metric_name) {
+            *count += 1;
+        } else {
+            inner
+                .collapsed_metric_series
+                .insert(metric_name.to_string(), 1);
+        }
+    }
+}
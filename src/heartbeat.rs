@@ -0,0 +1,217 @@
+use crate::{
+    convert::time_to_string,
+    models::{context_tag_keys, Data, DataPoint, DataPointType, Envelope, MetricData, Properties},
+    periodic::{PeriodicTask, Schedule},
+    tags::get_tags_from_attrs,
+    Error,
+};
+use opentelemetry::{trace::TraceResult, Context, Key};
+use opentelemetry_http::HttpClient;
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    runtime::RuntimeChannel,
+    trace::{Span, SpanProcessor},
+    Resource,
+};
+use opentelemetry_semantic_conventions as semcov;
+use std::{collections::BTreeMap, sync::Arc, time::Duration, time::SystemTime};
+
+const METRIC_HEARTBEAT_STATE: &str = "HeartbeatState";
+
+/// Azure App Service and Azure Functions environment variables and the heartbeat properties they
+/// are reported as, like in the other Application Insights SDKs.
+const AZURE_ENVIRONMENT_PROPERTIES: &[(&str, &str)] = &[
+    ("WEBSITE_SITE_NAME", "appSrv_SiteName"),
+    ("WEBSITE_HOME_STAMPNAME", "appSrv_wsStamp"),
+    ("WEBSITE_HOSTNAME", "appSrv_wsHost"),
+    ("WEBSITE_OWNER_NAME", "appSrv_wsOwner"),
+    ("WEBSITE_RESOURCE_GROUP", "appSrv_ResourceGroup"),
+];
+
+/// Periodically sends a `HeartbeatState` metric, which the Application Insights portal uses to
+/// show instance availability. The first heartbeat is sent right away.
+///
+/// This is a span processor, so it runs as long as the tracer provider. It ignores all spans.
+pub(crate) struct HeartbeatCollector<R: RuntimeChannel> {
+    task: PeriodicTask<R>,
+}
+
+impl<R: RuntimeChannel> std::fmt::Debug for HeartbeatCollector<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeartbeatCollector").finish()
+    }
+}
+
+impl<R: RuntimeChannel> HeartbeatCollector<R> {
+    pub(crate) fn new<C: HttpClient + 'static>(
+        client: Arc<C>,
+        endpoint: Arc<http::Uri>,
+        instrumentation_key: String,
+        resource: Resource,
+        interval: Duration,
+        extra_properties: BTreeMap<String, String>,
+        runtime: R,
+    ) -> HeartbeatCollector<R> {
+        let tags = get_tags_from_attrs(resource.iter());
+        let schedule = Schedule {
+            interval,
+            send_immediately: true,
+            send_on_shutdown: false,
+        };
+        let task = PeriodicTask::spawn(
+            client,
+            endpoint,
+            schedule,
+            Error::HeartbeatShutdown,
+            move || {
+                let properties = heartbeat_properties(
+                    &resource,
+                    tags.get(&context_tag_keys::INTERNAL_SDK_VERSION),
+                    &extra_properties,
+                    |name| std::env::var(name).ok(),
+                );
+                vec![Envelope {
+                    name: "Microsoft.ApplicationInsights.Metric",
+                    time: time_to_string(SystemTime::now()).into(),
+                    sample_rate: None,
+                    i_key: Some(instrumentation_key.clone().into()),
+                    tags: Some(tags.clone()),
+                    data: Some(Data::Metric(MetricData {
+                        ver: 2,
+                        metrics: vec![DataPoint {
+                            ns: None,
+                            name: METRIC_HEARTBEAT_STATE.into(),
+                            kind: Some(DataPointType::Measurement),
+                            // Number of properties which couldn't be determined. All of ours are
+                            // optional, so this is always 0.
+                            value: 0.,
+                        }],
+                        properties: Some(properties),
+                    })),
+                }]
+            },
+            runtime,
+        );
+        HeartbeatCollector { task }
+    }
+}
+
+impl<R: RuntimeChannel> SpanProcessor for HeartbeatCollector<R> {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, _span: SpanData) {}
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.task.shutdown()
+    }
+}
+
+/// Properties describing SDK, OS, runtime and Azure environment. Extra properties of the user
+/// override the ones determined here.
+fn heartbeat_properties(
+    resource: &Resource,
+    sdk_version: Option<&String>,
+    extra_properties: &BTreeMap<String, String>,
+    env_var: impl Fn(&str) -> Option<String>,
+) -> Properties {
+    let mut properties = Properties::new();
+    if let Some(sdk_version) = sdk_version {
+        properties.insert("sdk".into(), sdk_version.as_str().into());
+    }
+    let os_type = resource
+        .get(Key::from_static_str(semcov::resource::OS_TYPE))
+        .map(|v| v.as_str().into_owned())
+        .unwrap_or_else(|| std::env::consts::OS.into());
+    properties.insert("osType".into(), os_type.into());
+    let runtime_name = resource
+        .get(Key::from_static_str(semcov::resource::PROCESS_RUNTIME_NAME))
+        .map(|v| v.as_str().into_owned())
+        .unwrap_or_else(|| "rust".into());
+    let runtime = match resource.get(Key::from_static_str(
+        semcov::resource::PROCESS_RUNTIME_VERSION,
+    )) {
+        Some(version) => format!("{} {}", runtime_name, version.as_str()),
+        None => runtime_name,
+    };
+    properties.insert("runtimeFramework".into(), runtime.into());
+    for (env_name, property) in AZURE_ENVIRONMENT_PROPERTIES {
+        if let Some(value) = env_var(env_name) {
+            properties.insert((*property).into(), value.into());
+        }
+    }
+    if env_var("FUNCTIONS_WORKER_RUNTIME").is_some() {
+        if let Some(host) = env_var("WEBSITE_HOSTNAME") {
+            properties.insert("azfunction_appId".into(), host.into());
+        }
+    }
+    for (key, value) in extra_properties {
+        properties.insert(key.as_str().into(), value.as_str().into());
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::KeyValue;
+
+    #[test]
+    fn properties_from_resource_environment_and_extra_fields() {
+        let resource = Resource::new(vec![
+            KeyValue::new(semcov::resource::OS_TYPE, "linux"),
+            KeyValue::new(semcov::resource::PROCESS_RUNTIME_NAME, "rustc"),
+            KeyValue::new(semcov::resource::PROCESS_RUNTIME_VERSION, "1.76.0"),
+        ]);
+        let extra_properties = BTreeMap::from([
+            ("deployment".to_string(), "blue".to_string()),
+            ("osType".to_string(), "custom".to_string()),
+        ]);
+        let properties = heartbeat_properties(
+            &resource,
+            Some(&"opentelemetry:0.22.1".to_string()),
+            &extra_properties,
+            |name| match name {
+                "WEBSITE_SITE_NAME" => Some("my-site".into()),
+                "WEBSITE_HOSTNAME" => Some("my-site.azurewebsites.net".into()),
+                "FUNCTIONS_WORKER_RUNTIME" => Some("custom".into()),
+                _ => None,
+            },
+        );
+        let properties: Vec<_> = properties
+            .iter()
+            .map(|(k, v)| (k.as_ref(), v.as_ref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("appSrv_SiteName", "my-site"),
+                ("appSrv_wsHost", "my-site.azurewebsites.net"),
+                ("azfunction_appId", "my-site.azurewebsites.net"),
+                ("deployment", "blue"),
+                ("osType", "custom"),
+                ("runtimeFramework", "rustc 1.76.0"),
+                ("sdk", "opentelemetry:0.22.1"),
+            ],
+            properties
+        );
+    }
+
+    #[test]
+    fn properties_defaults() {
+        let properties = heartbeat_properties(&Resource::empty(), None, &BTreeMap::new(), |_| None);
+        let properties: Vec<_> = properties
+            .iter()
+            .map(|(k, v)| (k.as_ref(), v.as_ref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("osType", std::env::consts::OS),
+                ("runtimeFramework", "rust"),
+            ],
+            properties
+        );
+    }
+}
@@ -82,28 +82,25 @@
 Please note: Metrics are still experimental both in the OpenTelemetry specification as well as
 Rust implementation.
 
-Please note: The metrics export configuration is still a bit rough in this crate. But once
-configured it should work as expected.
-
 This requires the **metrics** feature.
 
+The pipeline builder can build a meter provider, which shares the HTTP client, connection string
+and resource with the tracer provider:
+
 ```no_run
 use opentelemetry::global;
-use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
 use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
-    // Setup exporter
-    let connection_string = std::env::var("APPLICATIONINSIGHTS_CONNECTION_STRING").unwrap();
-    let exporter = opentelemetry_application_insights::Exporter::new_from_connection_string(
-        connection_string,
-        reqwest::Client::new(),
-    )
-    .expect("valid connection string");
-    let reader = PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio).build();
-    let meter_provider = SdkMeterProvider::builder().with_reader(reader).build();
-    global::set_meter_provider(meter_provider);
+    let (tracer_provider, meter_provider) =
+        opentelemetry_application_insights::new_pipeline_from_env()
+            .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
+            .with_client(reqwest::Client::new())
+            .with_metrics_interval(Duration::from_secs(30))
+            .build_batch_with_meter_provider(opentelemetry_sdk::runtime::Tokio);
+    global::set_tracer_provider(tracer_provider);
+    global::set_meter_provider(meter_provider.clone());
 
     // Record value
     let meter = global::meter("example");
@@ -112,8 +109,66 @@ async fn main() {
 
     // Simulate work, during which metrics will periodically be reported.
     tokio::time::sleep(Duration::from_secs(300)).await;
+
+    meter_provider.shutdown().unwrap();
+}
+```
+
+For more control, e.g. over histogram percentiles or metric namespaces, configure the `Exporter`
+yourself and wrap it in a `PeriodicReader`:
+
+```no_run
+use opentelemetry::global;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+
+#[tokio::main]
+async fn main() {
+    let connection_string = std::env::var("APPLICATIONINSIGHTS_CONNECTION_STRING").unwrap();
+    let exporter = opentelemetry_application_insights::Exporter::new_from_connection_string(
+        connection_string,
+        reqwest::Client::new(),
+    )
+    .expect("valid connection string")
+    .with_histogram_percentiles([50., 95., 99.]);
+    let reader = PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio).build();
+    let meter_provider = SdkMeterProvider::builder().with_reader(reader).build();
+    global::set_meter_provider(meter_provider);
 }
 ```
+
+### Standard metrics
+
+The "Server requests", "Server response time", "Dependency calls" and "Failed requests" charts of
+the Application Insights portal use pre-aggregated standard metrics. Enable them with
+`with_standard_metrics` on the pipeline builder. This requires the `build_batch`/`install_batch`
+methods.
+"#
+)]
+#![cfg_attr(
+    feature = "performance-counters",
+    doc = r#"
+## Performance Counters
+
+Enable collection of performance counters of the current process (processor time, private bytes,
+available memory, thread and handle counts and IO rates) with `with_performance_counters` on the
+pipeline builder. They show up in the `performanceCounters` table.
+
+This requires the **performance-counters** feature _and_ the `build_batch`/`install_batch`
+methods.
+"#
+)]
+#![cfg_attr(
+    feature = "heartbeat",
+    doc = r#"
+## Heartbeat
+
+Enable heartbeats with `with_heartbeat` on the pipeline builder. Like the other Application Insights
+SDKs, a `HeartbeatState` metric is sent right away and then every 15 minutes (see
+`with_heartbeat_interval`). Its properties describe the SDK, OS, runtime (based on the `os.type`,
+`process.runtime.name` and `process.runtime.version` resource attributes) and Azure App Service or
+Functions environment. Add your own with `with_heartbeat_property`.
+
+This requires the **heartbeat** feature _and_ the `build_batch`/`install_batch` methods.
 "#
 )]
 #![cfg_attr(
@@ -126,12 +181,34 @@ Enable live metrics collection: <https://learn.microsoft.com/en-us/azure/azure-m
 Metrics are based on traces. See attribute mapping below for how traces are mapped to requests,
 dependencies and exceptions and how they are deemed "successful" or not.
 
+With the **live-metrics-logs** feature, log records can be included by adding the
+`live_metrics_log_processor` of the pipeline builder to a `LoggerProvider`. With the **metrics** feature, measurements of selected instruments can be
+included with `with_live_metrics_instruments` and `build_batch_with_meter_provider`.
+Application defined counters, e.g. a queue depth, can be added with `with_live_metrics_counter`.
+
 To configure role, instance, and machine name provide `service.name`, `service.instance.id`, and
 `host.name` resource attributes respectively in the trace config.
 
-Sample telemetry is not supported, yet.
+CPU usage and private bytes are the ones of the current process. Use
+`with_live_metrics_host_counters` to report CPU and memory usage of the whole host instead.
+
+Use `with_live_metrics_config` to change ping and post intervals, back-off behavior, add jitter to
+the first ping or omit host information. `live_metrics_status` tells whether live metrics are
+currently posted.
 
-This requires the **live-metrics** feature _and_ the `build_batch`/`install_batch` methods.
+Failed requests, failed dependencies, exceptions and traces show up as sample telemetry (at most
+30 per post, which happens every second).
+
+Custom charts and sample telemetry filters configured in the portal are supported for requests,
+dependencies, exceptions and traces. Durations in filters are in milliseconds or `hh:mm:ss`
+format. Once sample telemetry filters are configured, they replace the default selection above.
+To break request or dependency metrics down during an incident, add custom charts filtered by
+e.g. the request name or the dependency type.
+
+This requires the **live-metrics** feature. With the `build_batch`/`install_batch` methods live
+metrics are sent from a task on the given runtime. With `build_simple`/`install_simple` they are
+sent from a dedicated background thread instead, which needs an HTTP client that works without an
+async runtime, like `reqwest::blocking::Client`.
 
 ```no_run
 use opentelemetry::trace::Tracer as _;
@@ -190,6 +267,10 @@ async fn main() {
 //! | `service.instance.id`                                                      | Context: Cloud role instance (`ai.cloud.roleInstance`)   |
 //! | `telemetry.sdk.name` + `telemetry.sdk.version`                             | Context: Internal SDK version (`ai.internal.sdkVersion`) |
 //! | `SpanKind::Server` + `http.request.method` + `http.route`                  | Context: Operation Name (`ai.operation.name`)            |
+//! | Operation Name of the request (see `with_operation_name_propagation`)      | Context: Operation Name (`ai.operation.name`)            |
+//! | `SpanKind::Server` + `client.address` (if it is an IP address)             | Context: Client IP (`ai.location.ip`)                    |
+//! | `SpanKind::Server` + `user_agent.original` of known bots and health probes | Context: Synthetic source (`ai.operation.syntheticSource`) |
+//! | Attributes configured with `with_context_tag_attribute`                    | Context: configured tag                                  |
 //! | `ai.*`                                                                     | Context: AppInsights Tag (`ai.*`)                        |
 //! | `url.full`                                                                 | Dependency Data                                          |
 //! | `db.statement`                                                             | Dependency Data                                          |
@@ -210,7 +291,8 @@ async fn main() {
 //! | `client.socket.address`                                                    | Request Source                                           |
 //! | `http.response.status_code`                                                | Request Response code                                    |
 //!
-//! All other attributes are directly converted to custom properties.
+//! All other attributes are directly converted to custom properties. Array values are serialized
+//! as JSON, so they can be queried with `parse_json()`.
 //!
 //! For Requests the attributes `http.request.method` and `http.route` override the Name.
 //!
@@ -263,6 +345,9 @@ async fn main() {
 //!
 //! All other attributes are directly converted to custom properties.
 //!
+//! Events get the same context tags as their span. Event attributes starting with `ai.` override
+//! them (see [`attrs`]).
+//!
 //! [exceptions]: https://github.com/open-telemetry/opentelemetry-specification/blob/master/specification/trace/semantic_conventions/exceptions.md
 //! [Exception]: https://learn.microsoft.com/en-us/azure/azure-monitor/app/data-model-exception-telemetry
 //! [Event]: https://learn.microsoft.com/en-us/azure/azure-monitor/app/data-model-event-telemetry
@@ -274,11 +359,25 @@ async fn main() {
 //! Metrics get reported to Application Insights as Metric Data. The [`Aggregation`] determines how
 //! the data is represented.
 //!
-//! | Aggregator | Data representation                                                  |
-//! | ---------- | -------------------------------------------------------------------- |
-//! | Histogram  | aggregation with sum, count, min, and max (buckets are not exported) |
-//! | Gauge      | one measurement                                                      |
-//! | Sum        | aggregation with only a value                                        |
+//! | Aggregator           | Data representation                                                     |
+//! | -------------------- | ----------------------------------------------------------------------- |
+//! | Histogram            | aggregation with sum, count, min, max, and estimated standard deviation |
+//! | ExponentialHistogram | aggregation with sum, count, min, max, and estimated standard deviation |
+//! | Gauge                | one measurement                                                         |
+//! | Sum (monotonic)      | aggregation with only a value (the delta since the previous export)     |
+//! | Sum (non-monotonic)  | one measurement                                                         |
+//!
+//! The metric namespace is the name of the instrumentation scope (meter), unless it is mapped to
+//! a different namespace with `Exporter::with_metric_namespace` or overridden with the
+//! `ai.metric.namespace` attribute of a measurement.
+//!
+//! Metric and dimension names are trimmed and control characters in them are replaced with `_`.
+//! Metrics and dimensions with empty names and measurement attributes beyond the first 10 of a
+//! data point are dropped. Attributes of the resource and instrumentation scope don't count
+//! towards this limit. Each affected metric is reported once through the global error handler.
+//!
+//! Buckets of histograms are not exported. Configure `Exporter::with_histogram_percentiles` to
+//! export percentiles estimated from them as separate metrics.
 //!
 //! [`Aggregation`]: https://docs.rs/opentelemetry/0.20.0/opentelemetry/sdk/metrics/data/trait.Aggregation.html
 #![doc(html_root_url = "https://docs.rs/opentelemetry-application-insights/0.30.0")]
@@ -288,13 +387,27 @@ async fn main() {
 
 mod connection_string;
 mod convert;
+mod diagnostics;
+#[cfg(feature = "heartbeat")]
+mod heartbeat;
 #[cfg(feature = "metrics")]
 mod metrics;
 mod models;
+mod operation_name;
+#[cfg(feature = "performance-counters")]
+mod performance_counters;
+#[cfg(feature = "metrics")]
+mod periodic;
+#[cfg(any(feature = "live-metrics", feature = "performance-counters"))]
+mod process;
 #[cfg(feature = "live-metrics")]
 mod quick_pulse;
+#[cfg(feature = "live-metrics")]
+mod quick_pulse_configuration;
 #[cfg(doctest)]
 mod readme_test;
+#[cfg(feature = "metrics")]
+mod standard_metrics;
 mod tags;
 mod trace;
 mod uploader;
@@ -304,24 +417,55 @@ mod uploader_quick_pulse;
 #[cfg(feature = "live-metrics")]
 use connection_string::DEFAULT_LIVE_ENDPOINT;
 use connection_string::{ConnectionString, DEFAULT_BREEZE_ENDPOINT};
+pub use diagnostics::Diagnostics;
+#[cfg(feature = "heartbeat")]
+use heartbeat::HeartbeatCollector;
+#[cfg(feature = "metrics")]
+use metrics::{
+    CumulativeSums, DeltaTemporalitySelector, DimensionLimits, MetricSanitizer, MetricsExports,
+    MetricsPipeline,
+};
 pub use models::context_tag_keys::attrs;
-use opentelemetry::{global, trace::TracerProvider as _, KeyValue, Value};
+use opentelemetry::{global, trace::TracerProvider as _, Key, KeyValue, Value};
 pub use opentelemetry_http::HttpClient;
 #[cfg(feature = "metrics")]
-use opentelemetry_sdk::metrics::reader::{
-    AggregationSelector, DefaultAggregationSelector, DefaultTemporalitySelector,
-    TemporalitySelector,
+use opentelemetry_sdk::metrics::{
+    reader::{AggregationSelector, DefaultAggregationSelector, TemporalitySelector},
+    SdkMeterProvider, View,
 };
 use opentelemetry_sdk::{
     export::ExportError,
     runtime::RuntimeChannel,
-    trace::{Config, Tracer, TracerProvider},
+    trace::{Builder as TracerProviderBuilder, Config, Tracer, TracerProvider},
     Resource,
 };
 use opentelemetry_semantic_conventions as semcov;
+use operation_name::{OperationNameProcessor, OperationNames};
+#[cfg(feature = "performance-counters")]
+use performance_counters::PerformanceCountersCollector;
+#[cfg(feature = "live-metrics-logs")]
+pub use quick_pulse::LiveMetricsLogProcessor;
 #[cfg(feature = "live-metrics")]
-use quick_pulse::QuickPulseManager;
+pub use quick_pulse::{LiveMetricsConfig, LiveMetricsState, LiveMetricsStatus};
+#[cfg(feature = "live-metrics")]
+use quick_pulse::{QuickPulseCollector, QuickPulseManager, QuickPulseThreadManager};
+#[cfg(feature = "metrics")]
+use standard_metrics::StandardMetricsProcessor;
+#[cfg(feature = "live-metrics")]
+use std::borrow::Cow;
+#[cfg(feature = "heartbeat")]
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+#[cfg(any(feature = "metrics", feature = "performance-counters"))]
+use std::time::Duration;
 use std::{convert::TryInto, error::Error as StdError, fmt::Debug, sync::Arc};
+use tags::{push_tag_attribute, TagAttributes};
+
+#[cfg(feature = "performance-counters")]
+const DEFAULT_PERFORMANCE_COUNTERS_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "heartbeat")]
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15 * 60);
 
 /// Create a new Application Insights exporter pipeline builder
 #[deprecated(
@@ -337,8 +481,30 @@ pub fn new_pipeline(instrumentation_key: String) -> PipelineBuilder<()> {
         live_metrics_endpoint: http::Uri::from_static(DEFAULT_LIVE_ENDPOINT),
         #[cfg(feature = "live-metrics")]
         live_metrics: false,
+        #[cfg(feature = "live-metrics")]
+        live_metrics_collector: QuickPulseCollector::default(),
+        #[cfg(feature = "live-metrics")]
+        live_metrics_config: LiveMetricsConfig::default(),
         instrumentation_key,
         sample_rate: None,
+        truncation_marker: None,
+        diagnostics: Diagnostics::new(),
+        operation_name_propagation: false,
+        tag_attributes: TagAttributes::new(),
+        #[cfg(feature = "metrics")]
+        standard_metrics: false,
+        #[cfg(feature = "metrics")]
+        metrics: MetricsPipeline::default(),
+        #[cfg(feature = "performance-counters")]
+        performance_counters: false,
+        #[cfg(feature = "performance-counters")]
+        performance_counters_interval: DEFAULT_PERFORMANCE_COUNTERS_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat: false,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_properties: BTreeMap::new(),
     }
 }
 
@@ -366,8 +532,30 @@ pub fn new_pipeline_from_connection_string(
         live_metrics_endpoint: connection_string.live_endpoint,
         #[cfg(feature = "live-metrics")]
         live_metrics: false,
+        #[cfg(feature = "live-metrics")]
+        live_metrics_collector: QuickPulseCollector::default(),
+        #[cfg(feature = "live-metrics")]
+        live_metrics_config: LiveMetricsConfig::default(),
         instrumentation_key: connection_string.instrumentation_key,
         sample_rate: None,
+        truncation_marker: None,
+        diagnostics: Diagnostics::new(),
+        operation_name_propagation: false,
+        tag_attributes: TagAttributes::new(),
+        #[cfg(feature = "metrics")]
+        standard_metrics: false,
+        #[cfg(feature = "metrics")]
+        metrics: MetricsPipeline::default(),
+        #[cfg(feature = "performance-counters")]
+        performance_counters: false,
+        #[cfg(feature = "performance-counters")]
+        performance_counters_interval: DEFAULT_PERFORMANCE_COUNTERS_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat: false,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_properties: BTreeMap::new(),
     })
 }
 
@@ -395,8 +583,30 @@ pub fn new_pipeline_from_env(
         live_metrics_endpoint: connection_string.live_endpoint,
         #[cfg(feature = "live-metrics")]
         live_metrics: false,
+        #[cfg(feature = "live-metrics")]
+        live_metrics_collector: QuickPulseCollector::default(),
+        #[cfg(feature = "live-metrics")]
+        live_metrics_config: LiveMetricsConfig::default(),
         instrumentation_key: connection_string.instrumentation_key,
         sample_rate: None,
+        truncation_marker: None,
+        diagnostics: Diagnostics::new(),
+        operation_name_propagation: false,
+        tag_attributes: TagAttributes::new(),
+        #[cfg(feature = "metrics")]
+        standard_metrics: false,
+        #[cfg(feature = "metrics")]
+        metrics: MetricsPipeline::default(),
+        #[cfg(feature = "performance-counters")]
+        performance_counters: false,
+        #[cfg(feature = "performance-counters")]
+        performance_counters_interval: DEFAULT_PERFORMANCE_COUNTERS_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat: false,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        #[cfg(feature = "heartbeat")]
+        heartbeat_properties: BTreeMap::new(),
     })
 }
 
@@ -410,8 +620,30 @@ pub struct PipelineBuilder<C> {
     live_metrics_endpoint: http::Uri,
     #[cfg(feature = "live-metrics")]
     live_metrics: bool,
+    #[cfg(feature = "live-metrics")]
+    live_metrics_collector: QuickPulseCollector,
+    #[cfg(feature = "live-metrics")]
+    live_metrics_config: LiveMetricsConfig,
     instrumentation_key: String,
     sample_rate: Option<f64>,
+    truncation_marker: Option<String>,
+    diagnostics: Diagnostics,
+    operation_name_propagation: bool,
+    tag_attributes: TagAttributes,
+    #[cfg(feature = "metrics")]
+    standard_metrics: bool,
+    #[cfg(feature = "metrics")]
+    metrics: MetricsPipeline,
+    #[cfg(feature = "performance-counters")]
+    performance_counters: bool,
+    #[cfg(feature = "performance-counters")]
+    performance_counters_interval: Duration,
+    #[cfg(feature = "heartbeat")]
+    heartbeat: bool,
+    #[cfg(feature = "heartbeat")]
+    heartbeat_interval: Duration,
+    #[cfg(feature = "heartbeat")]
+    heartbeat_properties: BTreeMap<String, String>,
 }
 
 impl<C> PipelineBuilder<C> {
@@ -427,8 +659,30 @@ impl<C> PipelineBuilder<C> {
             live_metrics_endpoint: self.live_metrics_endpoint,
             #[cfg(feature = "live-metrics")]
             live_metrics: self.live_metrics,
+            #[cfg(feature = "live-metrics")]
+            live_metrics_collector: self.live_metrics_collector,
+            #[cfg(feature = "live-metrics")]
+            live_metrics_config: self.live_metrics_config,
             instrumentation_key: self.instrumentation_key,
             sample_rate: self.sample_rate,
+            truncation_marker: self.truncation_marker,
+            diagnostics: self.diagnostics,
+            operation_name_propagation: self.operation_name_propagation,
+            tag_attributes: self.tag_attributes,
+            #[cfg(feature = "metrics")]
+            standard_metrics: self.standard_metrics,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+            #[cfg(feature = "performance-counters")]
+            performance_counters: self.performance_counters,
+            #[cfg(feature = "performance-counters")]
+            performance_counters_interval: self.performance_counters_interval,
+            #[cfg(feature = "heartbeat")]
+            heartbeat: self.heartbeat,
+            #[cfg(feature = "heartbeat")]
+            heartbeat_interval: self.heartbeat_interval,
+            #[cfg(feature = "heartbeat")]
+            heartbeat_properties: self.heartbeat_properties,
         }
     }
 
@@ -483,6 +737,24 @@ impl<C> PipelineBuilder<C> {
         self
     }
 
+    /// Set a marker, which is appended to values truncated to the Application Insights length
+    /// limits, e.g. `"..."`. The value is shortened further, so the marker fits in the limit.
+    ///
+    /// Values are always truncated at character boundaries.
+    ///
+    /// Default: no marker
+    pub fn with_truncation_marker(mut self, marker: impl Into<String>) -> Self {
+        self.truncation_marker = Some(marker.into());
+        self
+    }
+
+    /// Set diagnostics, which count modifications the exporter makes to telemetry, e.g. how often
+    /// a field was truncated. See [`Diagnostics`].
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
     /// Assign the SDK config for the exporter pipeline.
     ///
     /// If there is an existing `sdk::Config` in the `PipelineBuilder` the `sdk::Resource`s
@@ -544,6 +816,262 @@ impl<C> PipelineBuilder<C> {
         }
     }
 
+    /// Set an Application Insights context tag from the value of a span attribute, e.g. the session
+    /// id from a `session.id` attribute. Useful for tags without a matching OpenTelemetry semantic
+    /// convention, like `ai.device.*`, `ai.user.id` and `ai.session.id`.
+    ///
+    /// The tag must be one of the keys in [`attrs`]. Other tags are ignored and reported as
+    /// [`Error::UnknownContextTag`] through the global error handler.
+    ///
+    /// Note: This example requires [`reqwest`] and the **opentelemetry-http/reqwest** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// use opentelemetry_application_insights::attrs as ai;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let tracer = opentelemetry_application_insights::new_pipeline_from_env()?
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_context_tag_attribute(ai::SESSION_ID, "session.id")
+    ///     .install_simple();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_context_tag_attribute(mut self, tag: &str, attribute: impl Into<Key>) -> Self {
+        push_tag_attribute(&mut self.tag_attributes, tag, attribute.into());
+        self
+    }
+
+    /// Propagate the operation name of requests to all telemetry within them.
+    ///
+    /// By default only requests (`SpanKind::Server` and `SpanKind::Consumer`) get an operation
+    /// name (`ai.operation.name`). If enabled, dependencies, traces and exceptions started within a
+    /// request in the same process get the operation name of the request, which makes the
+    /// "Operation" filters in the Application Insights portal work for them.
+    /// The spans themselves are not changed, so other exporters don't see the operation name.
+    ///
+    /// The operation name of a request is determined from the attributes its span is started
+    /// with, so `http.route` has to be set in the span builder to be propagated.
+    ///
+    /// Default: false
+    pub fn with_operation_name_propagation(self, propagate_operation_name: bool) -> Self {
+        PipelineBuilder {
+            operation_name_propagation: propagate_operation_name,
+            ..self
+        }
+    }
+
+    /// Enable pre-aggregated standard metrics for requests and dependencies.
+    ///
+    /// The standard metrics back the "Server requests", "Server response time", "Dependency calls"
+    /// and "Failed requests" charts of the Application Insights portal. They are aggregated from all
+    /// ended spans and sent every 60 seconds. Requests and dependencies get the
+    /// `_MS.ProcessedByMetricExtractors` property, so Application Insights doesn't count them twice.
+    /// Only sampled spans are seen, so counts and durations are scaled by the sample rate set with
+    /// `with_sample_rate`.
+    ///
+    /// Standard metrics need an async runtime and are only sent by pipelines built with
+    /// `build_batch` or `install_batch`.
+    ///
+    /// Default: false
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_standard_metrics(self, enable_standard_metrics: bool) -> Self {
+        PipelineBuilder {
+            standard_metrics: enable_standard_metrics,
+            ..self
+        }
+    }
+
+    /// Set the interval in which the meter provider exports metrics.
+    ///
+    /// Default: 60 seconds (or the `OTEL_METRIC_EXPORT_INTERVAL` environment variable)
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_interval(mut self, interval: Duration) -> Self {
+        self.metrics.interval = Some(interval);
+        self
+    }
+
+    /// Set the timeout of metrics exports of the meter provider.
+    ///
+    /// Default: 30 seconds (or the `OTEL_METRIC_EXPORT_TIMEOUT` environment variable)
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_timeout(mut self, timeout: Duration) -> Self {
+        self.metrics.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the temporality selector of the meter provider.
+    ///
+    /// Default: delta temporality for counters and histograms, cumulative temporality for up-down
+    /// counters and gauges
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_temporality_selector(
+        mut self,
+        temporality_selector: impl TemporalitySelector + 'static,
+    ) -> Self {
+        self.metrics.temporality_selector = Some(Box::new(temporality_selector));
+        self
+    }
+
+    /// Set the aggregation selector of the meter provider.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_aggregation_selector(
+        mut self,
+        aggregation_selector: impl AggregationSelector + 'static,
+    ) -> Self {
+        self.metrics.aggregation_selector = Some(Box::new(aggregation_selector));
+        self
+    }
+
+    /// Add a view to the meter provider.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_view(mut self, view: impl View) -> Self {
+        self.metrics.views.push(Box::new(view));
+        self
+    }
+
+    /// Set percentiles (between 0 and 100), which are estimated from the buckets of histograms.
+    ///
+    /// See [`Exporter::with_histogram_percentiles`].
+    ///
+    /// Default: no percentiles
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_histogram_percentiles(
+        mut self,
+        percentiles: impl IntoIterator<Item = f64>,
+    ) -> Self {
+        self.metrics.histogram_percentiles = percentiles
+            .into_iter()
+            .filter(|percentile| (0.0..=100.0).contains(percentile))
+            .collect();
+        self
+    }
+
+    /// Set the metric namespace for metrics of an instrumentation scope (meter name).
+    ///
+    /// See [`Exporter::with_metric_namespace`].
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_namespace(
+        mut self,
+        scope_name: impl Into<String>,
+        namespace: impl Into<String>,
+    ) -> Self {
+        self.metrics
+            .metric_namespaces
+            .insert(scope_name.into(), namespace.into());
+        self
+    }
+
+    /// Limit the number of series (unique combinations of dimension values) per metric.
+    ///
+    /// See [`Exporter::with_metric_series_limit`].
+    ///
+    /// Default: no limit
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_series_limit(mut self, series_limit: usize) -> Self {
+        self.metrics.dimension_limits.series_limit = Some(series_limit);
+        self
+    }
+
+    /// Never export the given attributes of metric data points as dimensions.
+    ///
+    /// See [`Exporter::with_dropped_metric_dimensions`].
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metrics_dropped_dimensions(
+        mut self,
+        dimensions: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Self {
+        self.metrics
+            .dimension_limits
+            .dropped_dimensions
+            .extend(dimensions.into_iter().map(Into::into));
+        self
+    }
+
+    /// Enable collection of performance counters of the current process, like processor time,
+    /// private bytes, available memory, thread and handle counts and IO rates.
+    ///
+    /// Private bytes and handle counts are only available on Linux.
+    ///
+    /// Performance counters need an async runtime and are only sent by pipelines built with
+    /// `build_batch` or `install_batch`.
+    ///
+    /// Default: false
+    #[cfg(feature = "performance-counters")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "performance-counters")))]
+    pub fn with_performance_counters(self, enable_performance_counters: bool) -> Self {
+        PipelineBuilder {
+            performance_counters: enable_performance_counters,
+            ..self
+        }
+    }
+
+    /// Set the interval in which performance counters are collected and sent. A zero interval is
+    /// ignored.
+    ///
+    /// Default: 60 seconds
+    #[cfg(feature = "performance-counters")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "performance-counters")))]
+    pub fn with_performance_counters_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.performance_counters_interval = interval;
+        }
+        self
+    }
+
+    /// Enable heartbeats, a `HeartbeatState` metric with properties describing SDK, OS, runtime
+    /// and Azure environment, which the Application Insights portal uses to show instance
+    /// availability.
+    ///
+    /// Heartbeats need an async runtime and are only sent by pipelines built with `build_batch` or
+    /// `install_batch`.
+    ///
+    /// Default: false
+    #[cfg(feature = "heartbeat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "heartbeat")))]
+    pub fn with_heartbeat(self, enable_heartbeat: bool) -> Self {
+        PipelineBuilder {
+            heartbeat: enable_heartbeat,
+            ..self
+        }
+    }
+
+    /// Set the interval in which heartbeats are sent. A zero interval is ignored.
+    ///
+    /// Default: 15 minutes
+    #[cfg(feature = "heartbeat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "heartbeat")))]
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.heartbeat_interval = interval;
+        }
+        self
+    }
+
+    /// Add a property to heartbeats, e.g. a deployment slot. It overrides a property with the same
+    /// name determined by the exporter.
+    #[cfg(feature = "heartbeat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "heartbeat")))]
+    pub fn with_heartbeat_property(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.heartbeat_properties.insert(key.into(), value.into());
+        self
+    }
+
     /// Enable live metrics.
     #[cfg(feature = "live-metrics")]
     #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
@@ -553,12 +1081,151 @@ impl<C> PipelineBuilder<C> {
             ..self
         }
     }
+
+    /// Report CPU and memory usage of the whole host in live metrics instead of the current
+    /// process. Shorthand for [`LiveMetricsConfig::with_host_counters`].
+    ///
+    /// Default: false
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    pub fn with_live_metrics_host_counters(mut self, enable_host_counters: bool) -> Self {
+        self.live_metrics_config = self
+            .live_metrics_config
+            .with_host_counters(enable_host_counters);
+        self
+    }
+
+    /// Set timings and behavior of live metrics. See [`LiveMetricsConfig`].
+    ///
+    /// This replaces the whole config, including host counters enabled with
+    /// `with_live_metrics_host_counters` before.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    pub fn with_live_metrics_config(self, config: LiveMetricsConfig) -> Self {
+        PipelineBuilder {
+            live_metrics_config: config,
+            ..self
+        }
+    }
+
+    /// Add an application defined counter to live metrics, e.g. a queue depth or cache hit rate.
+    ///
+    /// The callback is called once per post (every second while someone watches live metrics) and
+    /// should return quickly. Non-finite values are skipped. A counter with the same name replaces
+    /// the previous one. Names of built-in counters like `\ApplicationInsights\Requests/Sec` are
+    /// rejected and a counter whose callback panics is removed, both reported through the global
+    /// error handler.
+    ///
+    /// ```no_run
+    /// use std::sync::{
+    ///     atomic::{AtomicUsize, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// let queue_depth = Arc::new(AtomicUsize::new(0));
+    /// let tracer_provider = opentelemetry_application_insights::new_pipeline_from_env()
+    ///     .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_live_metrics(true)
+    ///     .with_live_metrics_counter("Queue depth", {
+    ///         let queue_depth = queue_depth.clone();
+    ///         move || queue_depth.load(Ordering::Relaxed) as f64
+    ///     })
+    ///     .build_simple();
+    /// ```
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    pub fn with_live_metrics_counter(
+        self,
+        name: impl Into<Cow<'static, str>>,
+        callback: impl Fn() -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        self.live_metrics_collector
+            .add_counter(name.into(), Arc::new(callback));
+        self
+    }
+
+    /// Send measurements of the instruments with the given names to live metrics as well.
+    ///
+    /// Counters show the sum, gauges and up-down counters the latest value and histograms the
+    /// average of the measurements of each second. This only works for meter providers built with
+    /// `build_batch_with_meter_provider` and live metrics enabled. It's ignored by
+    /// `build_meter_provider`, because live metrics run as part of the tracer provider.
+    #[cfg(all(feature = "live-metrics", feature = "metrics"))]
+    #[cfg_attr(docsrs, doc(cfg(all(feature = "live-metrics", feature = "metrics"))))]
+    pub fn with_live_metrics_instruments(
+        mut self,
+        instruments: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.metrics
+            .live_metrics_instruments
+            .extend(instruments.into_iter().map(Into::into));
+        self
+    }
+
+    /// Log processor, which feeds log records into live metrics of this pipeline. Log records with
+    /// `exception.*` attributes count as exceptions. All log records show up as sample telemetry.
+    ///
+    /// Add it to your `LoggerProvider`. It only collects data once the pipeline is built with
+    /// live metrics enabled.
+    ///
+    /// ```no_run
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let pipeline = opentelemetry_application_insights::new_pipeline_from_env()?
+    ///     .with_client(reqwest::Client::new())
+    ///     .with_live_metrics(true);
+    /// let logger_provider = opentelemetry_sdk::logs::LoggerProvider::builder()
+    ///     .with_log_processor(pipeline.live_metrics_log_processor())
+    ///     .build();
+    /// let tracer_provider = pipeline.build_batch(opentelemetry_sdk::runtime::Tokio);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "live-metrics-logs")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics-logs")))]
+    pub fn live_metrics_log_processor(&self) -> LiveMetricsLogProcessor {
+        LiveMetricsLogProcessor::new(self.live_metrics_collector.clone())
+    }
+
+    /// Status of live metrics of this pipeline, which tells whether live metrics are idle, pinging,
+    /// posting or backed off after failed requests.
+    ///
+    /// ```no_run
+    /// use opentelemetry_application_insights::LiveMetricsState;
+    ///
+    /// let pipeline = opentelemetry_application_insights::new_pipeline_from_env()
+    ///     .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_live_metrics(true);
+    /// let status = pipeline.live_metrics_status();
+    /// let tracer_provider = pipeline.build_simple();
+    ///
+    /// // ... send traces ...
+    ///
+    /// if status.state() == LiveMetricsState::Posting {
+    ///     println!("someone is watching live metrics");
+    /// }
+    /// ```
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    pub fn live_metrics_status(&self) -> LiveMetricsStatus {
+        self.live_metrics_collector.status()
+    }
 }
 
 impl<C> PipelineBuilder<C>
 where
     C: HttpClient + 'static,
 {
+    fn resource(&self) -> Resource {
+        let mut resource = Resource::default();
+        if let Some(ref config) = self.config {
+            resource = resource.merge(config.resource.as_ref());
+        }
+        resource
+    }
+
     fn init_exporter(self) -> Exporter<C> {
         Exporter {
             client: Arc::new(self.client),
@@ -567,18 +1234,72 @@ where
             ),
             instrumentation_key: self.instrumentation_key,
             sample_rate: self.sample_rate.unwrap_or(100.0),
+            truncation_marker: self.truncation_marker,
+            diagnostics: self.diagnostics,
+            tag_attributes: self.tag_attributes,
+            operation_names: None,
             #[cfg(feature = "metrics")]
-            temporality_selector: Box::new(DefaultTemporalitySelector::new()),
+            temporality_selector: Box::new(DeltaTemporalitySelector),
             #[cfg(feature = "metrics")]
             aggregation_selector: Box::new(DefaultAggregationSelector::new()),
+            #[cfg(feature = "metrics")]
+            histogram_percentiles: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metric_namespaces: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            cumulative_sums: CumulativeSums::default(),
+            #[cfg(feature = "metrics")]
+            metrics_exports: MetricsExports::default(),
+            #[cfg(feature = "metrics")]
+            dimension_limits: DimensionLimits::default(),
+            #[cfg(feature = "metrics")]
+            metric_sanitizer: MetricSanitizer::default(),
+            #[cfg(feature = "metrics")]
+            standard_metrics: false,
         }
     }
 
     /// Build a configured `TracerProvider` with a simple span processor.
+    ///
+    /// Live metrics, if enabled, are sent from a dedicated background thread, which blocks on the
+    /// HTTP client. This needs a client which works without an async runtime, like
+    /// `reqwest::blocking::Client`. With other clients live metrics stop and report
+    /// [`Error::QuickPulseThreadPanic`].
+    ///
+    /// Standard metrics, performance counters and heartbeats need an async runtime. They are not
+    /// sent by this pipeline, even if enabled. Use `build_batch` for them.
     pub fn build_simple(mut self) -> TracerProvider {
+        #[cfg(feature = "live-metrics")]
+        let resource = self.resource();
         let config = self.config.take();
-        let exporter = self.init_exporter();
-        let mut builder = TracerProvider::builder().with_simple_exporter(exporter);
+        #[cfg(feature = "live-metrics")]
+        let live_metrics = self.live_metrics;
+        #[cfg(feature = "live-metrics")]
+        let live_metrics_endpoint = self.live_metrics_endpoint.clone();
+        #[cfg(feature = "live-metrics")]
+        let live_metrics_collector = self.live_metrics_collector.clone();
+        #[cfg(feature = "live-metrics")]
+        let live_metrics_config = self.live_metrics_config.clone();
+        let operation_name_propagation = self.operation_name_propagation;
+        let mut exporter = self.init_exporter();
+        let mut builder = TracerProvider::builder();
+        if operation_name_propagation {
+            let operation_names = Arc::new(OperationNames::default());
+            exporter.operation_names = Some(operation_names.clone());
+            builder = builder.with_span_processor(OperationNameProcessor::new(operation_names));
+        }
+        #[cfg(feature = "live-metrics")]
+        if live_metrics {
+            builder = builder.with_span_processor(QuickPulseThreadManager::new(
+                exporter.client.clone(),
+                live_metrics_endpoint,
+                exporter.instrumentation_key.clone(),
+                resource,
+                live_metrics_collector,
+                live_metrics_config,
+            ));
+        }
+        builder = builder.with_simple_exporter(exporter);
         if let Some(config) = config {
             builder = builder.with_config(config);
         }
@@ -588,34 +1309,157 @@ where
 
     /// Build a configured `TracerProvider` with a batch span processor using the specified
     /// runtime.
-    pub fn build_batch<R: RuntimeChannel>(mut self, runtime: R) -> TracerProvider {
+    pub fn build_batch<R: RuntimeChannel>(self, runtime: R) -> TracerProvider {
+        self.batch_tracer_provider(runtime).build()
+    }
+
+    /// Build a configured `TracerProvider` with a batch span processor and a configured
+    /// `SdkMeterProvider` with a periodic reader using the specified runtime.
+    ///
+    /// Both share the HTTP client, connection string and resource. Configure the meter provider
+    /// with the `with_metrics_*` methods.
+    ///
+    /// ```no_run
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let (tracer_provider, meter_provider) =
+    ///     opentelemetry_application_insights::new_pipeline_from_env()?
+    ///         .with_client(reqwest::Client::new())
+    ///         .with_metrics_interval(std::time::Duration::from_secs(30))
+    ///         .build_batch_with_meter_provider(opentelemetry_sdk::runtime::Tokio);
+    /// opentelemetry::global::set_tracer_provider(tracer_provider);
+    /// opentelemetry::global::set_meter_provider(meter_provider);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn build_batch_with_meter_provider<R: RuntimeChannel>(
+        mut self,
+        runtime: R,
+    ) -> (TracerProvider, SdkMeterProvider) {
+        #[allow(unused_mut)]
+        let mut metrics = std::mem::take(&mut self.metrics);
+        #[cfg(feature = "live-metrics")]
+        if self.live_metrics {
+            metrics.live_metrics_collector = Some(self.live_metrics_collector.clone());
+        }
+        let tracer_provider = self.batch_tracer_provider(runtime.clone());
+        let meter_provider = metrics.build(
+            &tracer_provider.exporter,
+            tracer_provider.resource.clone(),
+            runtime,
+        );
+        (tracer_provider.build(), meter_provider)
+    }
+
+    /// Build a configured `SdkMeterProvider` with a periodic reader using the specified runtime.
+    ///
+    /// Configure it with the `with_metrics_*` methods. Use `build_batch_with_meter_provider` if
+    /// you need a `TracerProvider` as well. Measurements aren't sent to live metrics (see
+    /// `with_live_metrics_instruments`).
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn build_meter_provider<R: RuntimeChannel>(mut self, runtime: R) -> SdkMeterProvider {
+        let metrics = std::mem::take(&mut self.metrics);
+        let resource = self.resource();
+        let exporter = self.init_exporter();
+        metrics.build(&exporter, resource, runtime)
+    }
+
+    fn batch_tracer_provider<R: RuntimeChannel>(mut self, runtime: R) -> BatchTracerProvider<C, R> {
+        let resource = self.resource();
         let config = self.config.take();
         #[cfg(feature = "live-metrics")]
         let live_metrics = self.live_metrics;
         #[cfg(feature = "live-metrics")]
         let live_metrics_endpoint = self.live_metrics_endpoint.clone();
-        let exporter = self.init_exporter();
+        #[cfg(feature = "live-metrics")]
+        let live_metrics_collector = self.live_metrics_collector.clone();
+        #[cfg(feature = "live-metrics")]
+        let live_metrics_config = self.live_metrics_config.clone();
+        let operation_name_propagation = self.operation_name_propagation;
+        #[cfg(feature = "metrics")]
+        let standard_metrics = self.standard_metrics;
+        #[cfg(feature = "performance-counters")]
+        let performance_counters = self.performance_counters;
+        #[cfg(feature = "performance-counters")]
+        let performance_counters_interval = self.performance_counters_interval;
+        #[cfg(feature = "heartbeat")]
+        let heartbeat = self.heartbeat;
+        #[cfg(feature = "heartbeat")]
+        let heartbeat_interval = self.heartbeat_interval;
+        #[cfg(feature = "heartbeat")]
+        let heartbeat_properties = std::mem::take(&mut self.heartbeat_properties);
+        #[allow(unused_mut)]
+        let mut exporter = self.init_exporter();
         let mut builder = TracerProvider::builder();
+        if operation_name_propagation {
+            let operation_names = Arc::new(OperationNames::default());
+            exporter.operation_names = Some(operation_names.clone());
+            builder = builder.with_span_processor(OperationNameProcessor::new(operation_names));
+        }
         #[cfg(feature = "live-metrics")]
         if live_metrics {
-            let mut resource = Resource::default();
-            if let Some(ref config) = config {
-                resource = resource.merge(config.resource.as_ref());
-            };
             builder = builder.with_span_processor(QuickPulseManager::new(
                 exporter.client.clone(),
                 live_metrics_endpoint,
                 exporter.instrumentation_key.clone(),
-                resource,
+                resource.clone(),
+                live_metrics_collector,
+                live_metrics_config,
+                runtime.clone(),
+            ));
+        }
+        #[cfg(feature = "performance-counters")]
+        if performance_counters {
+            builder = builder.with_span_processor(PerformanceCountersCollector::new(
+                exporter.client.clone(),
+                exporter.endpoint.clone(),
+                exporter.instrumentation_key.clone(),
+                resource.clone(),
+                performance_counters_interval,
                 runtime.clone(),
             ));
         }
-        builder = builder.with_batch_exporter(exporter, runtime);
+        #[cfg(feature = "heartbeat")]
+        if heartbeat {
+            builder = builder.with_span_processor(HeartbeatCollector::new(
+                exporter.client.clone(),
+                exporter.endpoint.clone(),
+                exporter.instrumentation_key.clone(),
+                resource.clone(),
+                heartbeat_interval,
+                heartbeat_properties,
+                runtime.clone(),
+            ));
+        }
+        #[cfg(feature = "metrics")]
+        let standard_metrics_processor = if standard_metrics {
+            exporter.standard_metrics = true;
+            Some(StandardMetricsProcessor::new(
+                exporter.client.clone(),
+                exporter.endpoint.clone(),
+                exporter.instrumentation_key.clone(),
+                resource.clone(),
+                exporter.sample_rate,
+                runtime.clone(),
+            ))
+        } else {
+            None
+        };
         if let Some(config) = config {
             builder = builder.with_config(config);
         }
 
-        builder.build()
+        BatchTracerProvider {
+            builder,
+            exporter,
+            resource,
+            runtime,
+            #[cfg(feature = "metrics")]
+            standard_metrics_processor,
+        }
     }
 
     /// Install an Application Insights pipeline with the recommended defaults.
@@ -657,10 +1501,28 @@ pub struct Exporter<C> {
     endpoint: Arc<http::Uri>,
     instrumentation_key: String,
     sample_rate: f64,
+    truncation_marker: Option<String>,
+    diagnostics: Diagnostics,
+    tag_attributes: TagAttributes,
+    operation_names: Option<Arc<OperationNames>>,
     #[cfg(feature = "metrics")]
     temporality_selector: Box<dyn TemporalitySelector>,
     #[cfg(feature = "metrics")]
     aggregation_selector: Box<dyn AggregationSelector>,
+    #[cfg(feature = "metrics")]
+    histogram_percentiles: Vec<f64>,
+    #[cfg(feature = "metrics")]
+    metric_namespaces: HashMap<String, String>,
+    #[cfg(feature = "metrics")]
+    cumulative_sums: CumulativeSums,
+    #[cfg(feature = "metrics")]
+    metrics_exports: MetricsExports,
+    #[cfg(feature = "metrics")]
+    dimension_limits: DimensionLimits,
+    #[cfg(feature = "metrics")]
+    metric_sanitizer: MetricSanitizer,
+    #[cfg(feature = "metrics")]
+    standard_metrics: bool,
 }
 
 impl<C: Debug> Debug for Exporter<C> {
@@ -670,7 +1532,10 @@ impl<C: Debug> Debug for Exporter<C> {
             .field("client", &self.client)
             .field("endpoint", &self.endpoint)
             .field("instrumentation_key", &self.instrumentation_key)
-            .field("sample_rate", &self.sample_rate);
+            .field("sample_rate", &self.sample_rate)
+            .field("truncation_marker", &self.truncation_marker)
+            .field("diagnostics", &self.diagnostics)
+            .field("tag_attributes", &self.tag_attributes);
         debug.finish()
     }
 }
@@ -687,10 +1552,28 @@ impl<C> Exporter<C> {
             ),
             instrumentation_key,
             sample_rate: 100.0,
+            truncation_marker: None,
+            diagnostics: Diagnostics::new(),
+            tag_attributes: TagAttributes::new(),
+            operation_names: None,
             #[cfg(feature = "metrics")]
-            temporality_selector: Box::new(DefaultTemporalitySelector::new()),
+            temporality_selector: Box::new(DeltaTemporalitySelector),
             #[cfg(feature = "metrics")]
             aggregation_selector: Box::new(DefaultAggregationSelector::new()),
+            #[cfg(feature = "metrics")]
+            histogram_percentiles: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metric_namespaces: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            cumulative_sums: CumulativeSums::default(),
+            #[cfg(feature = "metrics")]
+            metrics_exports: MetricsExports::default(),
+            #[cfg(feature = "metrics")]
+            dimension_limits: DimensionLimits::default(),
+            #[cfg(feature = "metrics")]
+            metric_sanitizer: MetricSanitizer::default(),
+            #[cfg(feature = "metrics")]
+            standard_metrics: false,
         }
     }
 
@@ -708,10 +1591,28 @@ impl<C> Exporter<C> {
             ),
             instrumentation_key: connection_string.instrumentation_key,
             sample_rate: 100.0,
+            truncation_marker: None,
+            diagnostics: Diagnostics::new(),
+            tag_attributes: TagAttributes::new(),
+            operation_names: None,
             #[cfg(feature = "metrics")]
-            temporality_selector: Box::new(DefaultTemporalitySelector::new()),
+            temporality_selector: Box::new(DeltaTemporalitySelector),
             #[cfg(feature = "metrics")]
             aggregation_selector: Box::new(DefaultAggregationSelector::new()),
+            #[cfg(feature = "metrics")]
+            histogram_percentiles: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metric_namespaces: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            cumulative_sums: CumulativeSums::default(),
+            #[cfg(feature = "metrics")]
+            metrics_exports: MetricsExports::default(),
+            #[cfg(feature = "metrics")]
+            dimension_limits: DimensionLimits::default(),
+            #[cfg(feature = "metrics")]
+            metric_sanitizer: MetricSanitizer::default(),
+            #[cfg(feature = "metrics")]
+            standard_metrics: false,
         })
     }
 
@@ -738,7 +1639,41 @@ impl<C> Exporter<C> {
         self
     }
 
+    /// Set a marker, which is appended to values truncated to the Application Insights length
+    /// limits, e.g. `"..."`. The value is shortened further, so the marker fits in the limit.
+    ///
+    /// Values are always truncated at character boundaries.
+    ///
+    /// Default: no marker
+    pub fn with_truncation_marker(mut self, marker: impl Into<String>) -> Self {
+        self.truncation_marker = Some(marker.into());
+        self
+    }
+
+    /// Set diagnostics, which count modifications the exporter makes to telemetry, e.g. how often
+    /// a field was truncated. See [`Diagnostics`].
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Set an Application Insights context tag from the value of a span attribute, e.g. the session
+    /// id from a `session.id` attribute. Useful for tags without a matching OpenTelemetry semantic
+    /// convention, like `ai.device.*`, `ai.user.id` and `ai.session.id`.
+    ///
+    /// The tag must be one of the keys in [`attrs`]. Other tags are ignored and reported as
+    /// [`Error::UnknownContextTag`] through the global error handler.
+    pub fn with_context_tag_attribute(mut self, tag: &str, attribute: impl Into<Key>) -> Self {
+        push_tag_attribute(&mut self.tag_attributes, tag, attribute.into());
+        self
+    }
+
     /// Set temporality selector.
+    ///
+    /// Monotonic sums with cumulative temporality are converted to deltas by the exporter.
+    ///
+    /// Default: delta temporality for counters and histograms, cumulative temporality for up-down
+    /// counters and gauges
     #[cfg(feature = "metrics")]
     #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
     pub fn with_temporality_selector(
@@ -759,6 +1694,101 @@ impl<C> Exporter<C> {
         self.aggregation_selector = Box::new(aggregation_selector);
         self
     }
+
+    /// Set percentiles (between 0 and 100), which are estimated from the buckets of histograms.
+    ///
+    /// Each percentile is exported as a separate metric named `<name>.p<percentile>`, e.g.
+    /// `http.server.duration.p95`. Percentiles are estimated by assuming the measurements are
+    /// evenly distributed within each bucket, so their accuracy depends on the bucket boundaries.
+    ///
+    /// Default: no percentiles
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_histogram_percentiles(
+        mut self,
+        percentiles: impl IntoIterator<Item = f64>,
+    ) -> Self {
+        self.histogram_percentiles = percentiles
+            .into_iter()
+            .filter(|percentile| (0.0..=100.0).contains(percentile))
+            .collect();
+        self
+    }
+
+    /// Set the metric namespace for metrics of an instrumentation scope (meter name).
+    ///
+    /// By default, the namespace of metrics is the name of the instrumentation scope. Individual
+    /// measurements can override the namespace with the `ai.metric.namespace` attribute.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metric_namespace(
+        mut self,
+        scope_name: impl Into<String>,
+        namespace: impl Into<String>,
+    ) -> Self {
+        self.metric_namespaces
+            .insert(scope_name.into(), namespace.into());
+        self
+    }
+
+    /// Limit the number of series (unique combinations of dimension values) per metric.
+    ///
+    /// Once a metric reached the limit, data points of new series are exported with all dimension
+    /// values set to `Other` and merged into one data point per export: values are added up and
+    /// measurements, like values of gauges, become aggregations with their count, minimum and
+    /// maximum. Series which weren't exported in the last 10 exports no longer count towards the
+    /// limit. The number of collapsed data points is counted in
+    /// [`Diagnostics::collapsed_metric_series`].
+    ///
+    /// Default: no limit
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_metric_series_limit(mut self, series_limit: usize) -> Self {
+        self.dimension_limits.series_limit = Some(series_limit);
+        self
+    }
+
+    /// Never export the given attributes of metric data points as dimensions, e.g. high
+    /// cardinality attributes like user ids or URLs.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    pub fn with_dropped_metric_dimensions(
+        mut self,
+        dimensions: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Self {
+        self.dimension_limits
+            .dropped_dimensions
+            .extend(dimensions.into_iter().map(Into::into));
+        self
+    }
+}
+
+/// Tracer provider of a batch pipeline, which still needs the batch span processor for the
+/// exporter. The exporter is exposed, so the meter provider can share its settings.
+struct BatchTracerProvider<C, R: RuntimeChannel> {
+    builder: TracerProviderBuilder,
+    exporter: Exporter<C>,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    resource: Resource,
+    runtime: R,
+    #[cfg(feature = "metrics")]
+    standard_metrics_processor: Option<StandardMetricsProcessor<R>>,
+}
+
+impl<C: HttpClient + 'static, R: RuntimeChannel> BatchTracerProvider<C, R> {
+    fn build(self) -> TracerProvider {
+        #[allow(unused_mut)]
+        let mut builder = self
+            .builder
+            .with_batch_exporter(self.exporter, self.runtime);
+        // Added after the batch exporter, which waits for the export on shutdown, so spans are
+        // sent before the last standard metrics.
+        #[cfg(feature = "metrics")]
+        if let Some(standard_metrics_processor) = self.standard_metrics_processor {
+            builder = builder.with_span_processor(standard_metrics_processor);
+        }
+        builder.build()
+    }
 }
 
 fn append_v2_track(uri: impl ToString) -> Result<http::Uri, http::uri::InvalidUri> {
@@ -801,6 +1831,11 @@ pub enum Error {
     #[error("upload failed with {0}")]
     Upload(String),
 
+    /// A context tag configured with `with_context_tag_attribute` is not one of the keys in
+    /// [`attrs`]. The tag is ignored.
+    #[error("unknown context tag {0:?}")]
+    UnknownContextTag(String),
+
     /// Failed to process span for live metrics.
     #[cfg(feature = "live-metrics")]
     #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
@@ -812,6 +1847,77 @@ pub enum Error {
     #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
     #[error("stop live metrics failed with {0}")]
     QuickPulseShutdown(opentelemetry_sdk::runtime::TrySendError),
+
+    /// Live metrics were stopped already, e.g. by an earlier shutdown or because the runtime shut
+    /// down.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("live metrics stopped already")]
+    QuickPulseStopped,
+
+    /// Live metrics didn't stop within the shutdown timeout.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("stop live metrics timed out")]
+    QuickPulseShutdownTimeout,
+
+    /// Failed to start the live metrics thread used with the simple span processor.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("start live metrics thread failed with {0}")]
+    QuickPulseThread(std::io::Error),
+
+    /// The live metrics thread used with the simple span processor panicked and stopped. This
+    /// happens with HTTP clients which need an async runtime, like the async `reqwest::Client`.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("live metrics thread panicked with {0:?}")]
+    QuickPulseThreadPanic(String),
+
+    /// Live metrics collection configuration contains an invalid custom chart or sample telemetry
+    /// filter, which is ignored.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("invalid live metrics collection configuration: {0}")]
+    QuickPulseCollectionConfiguration(String),
+
+    /// An application defined live metrics counter has the name of a built-in counter and is
+    /// ignored.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("live metrics counter {0:?} has the name of a built-in counter")]
+    QuickPulseCounterName(String),
+
+    /// The callback of an application defined live metrics counter panicked. The counter is
+    /// removed.
+    #[cfg(feature = "live-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "live-metrics")))]
+    #[error("live metrics counter {0:?} panicked with {1:?}")]
+    QuickPulseCounterPanic(String, String),
+
+    /// Failed to flush standard metrics.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    #[error("flush standard metrics failed with {0}")]
+    StandardMetricsFlush(opentelemetry_sdk::runtime::TrySendError),
+
+    /// Failed to stop standard metrics.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    #[error("stop standard metrics failed with {0}")]
+    StandardMetricsShutdown(opentelemetry_sdk::runtime::TrySendError),
+
+    /// Failed to stop performance counters.
+    #[cfg(feature = "performance-counters")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "performance-counters")))]
+    #[error("stop performance counters failed with {0}")]
+    PerformanceCountersShutdown(opentelemetry_sdk::runtime::TrySendError),
+
+    /// Failed to stop heartbeats.
+    #[cfg(feature = "heartbeat")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "heartbeat")))]
+    #[error("stop heartbeats failed with {0}")]
+    HeartbeatShutdown(opentelemetry_sdk::runtime::TrySendError),
 }
 
 impl ExportError for Error {
@@ -1,25 +1,730 @@
+#[cfg(feature = "live-metrics")]
+use crate::quick_pulse::{LiveMetricValue, QuickPulseCollector};
 use crate::{
     convert::time_to_string,
-    models::{Data, DataPoint, DataPointType, Envelope, MetricData, Properties},
+    models::{finish_truncation, Data, DataPoint, DataPointType, Envelope, MetricData, Properties},
     tags::get_tags_for_metric,
-    Exporter,
+    Diagnostics, Exporter,
 };
 use async_trait::async_trait;
 use opentelemetry::{
     global,
     metrics::{MetricsError, Result as MetricsResult},
+    Key, KeyValue, Value,
 };
 use opentelemetry_http::HttpClient;
 use opentelemetry_sdk::{
     metrics::{
-        data::{Gauge, Histogram, Metric, ResourceMetrics, Sum, Temporality},
+        data::{
+            ExponentialBucket, ExponentialHistogram, ExponentialHistogramDataPoint, Gauge,
+            Histogram, HistogramDataPoint, Metric, ResourceMetrics, Sum, Temporality,
+        },
         exporter::PushMetricsExporter,
-        reader::{AggregationSelector, TemporalitySelector},
-        Aggregation, InstrumentKind,
+        reader::{AggregationSelector, DefaultAggregationSelector, TemporalitySelector},
+        Aggregation, InstrumentKind, PeriodicReader, SdkMeterProvider, View,
     },
-    AttributeSet,
+    runtime::Runtime,
+    AttributeSet, Resource,
 };
-use std::{convert::TryInto, sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    future::poll_fn,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::{Poll, Waker},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Attribute of a measurement which overrides the metric namespace.
+const METRIC_NAMESPACE: &str = "ai.metric.namespace";
+
+/// Meter provider configuration of the pipeline builder.
+#[derive(Default)]
+pub(crate) struct MetricsPipeline {
+    pub(crate) interval: Option<Duration>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) temporality_selector: Option<Box<dyn TemporalitySelector>>,
+    pub(crate) aggregation_selector: Option<Box<dyn AggregationSelector>>,
+    pub(crate) views: Vec<Box<dyn View>>,
+    pub(crate) histogram_percentiles: Vec<f64>,
+    pub(crate) metric_namespaces: HashMap<String, String>,
+    pub(crate) dimension_limits: DimensionLimits,
+    /// Names of instruments which are sent to live metrics as well.
+    #[cfg(feature = "live-metrics")]
+    pub(crate) live_metrics_instruments: HashSet<String>,
+    /// Set when the meter provider is built together with a tracer provider with live metrics.
+    #[cfg(feature = "live-metrics")]
+    pub(crate) live_metrics_collector: Option<QuickPulseCollector>,
+}
+
+impl std::fmt::Debug for MetricsPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsPipeline")
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl MetricsPipeline {
+    /// Builds a meter provider with a metrics exporter which shares the client, endpoint and
+    /// settings of the given trace exporter.
+    pub(crate) fn build<C, R>(
+        self,
+        exporter: &Exporter<C>,
+        resource: Resource,
+        runtime: R,
+    ) -> SdkMeterProvider
+    where
+        C: HttpClient + 'static,
+        R: Runtime,
+    {
+        let metrics_exporter = Exporter {
+            client: exporter.client.clone(),
+            endpoint: exporter.endpoint.clone(),
+            instrumentation_key: exporter.instrumentation_key.clone(),
+            sample_rate: exporter.sample_rate,
+            truncation_marker: exporter.truncation_marker.clone(),
+            diagnostics: exporter.diagnostics.clone(),
+            tag_attributes: exporter.tag_attributes.clone(),
+            operation_names: None,
+            temporality_selector: self
+                .temporality_selector
+                .unwrap_or_else(|| Box::new(DeltaTemporalitySelector)),
+            aggregation_selector: self
+                .aggregation_selector
+                .unwrap_or_else(|| Box::new(DefaultAggregationSelector::new())),
+            histogram_percentiles: self.histogram_percentiles,
+            metric_namespaces: self.metric_namespaces,
+            cumulative_sums: CumulativeSums::default(),
+            metrics_exports: MetricsExports::default(),
+            dimension_limits: self.dimension_limits,
+            metric_sanitizer: MetricSanitizer::default(),
+            standard_metrics: false,
+        };
+        let mut builder = SdkMeterProvider::builder().with_resource(resource);
+        #[cfg(feature = "live-metrics")]
+        if let Some(collector) = self.live_metrics_collector {
+            if !self.live_metrics_instruments.is_empty() {
+                let live_metrics_exporter = LiveMetricsExporter {
+                    collector,
+                    instruments: self.live_metrics_instruments,
+                };
+                builder = builder.with_reader(
+                    PeriodicReader::builder(live_metrics_exporter, runtime.clone())
+                        .with_interval(LIVE_METRICS_INTERVAL)
+                        .build(),
+                );
+            }
+        }
+        let mut reader = PeriodicReader::builder(metrics_exporter, runtime);
+        if let Some(interval) = self.interval {
+            reader = reader.with_interval(interval);
+        }
+        if let Some(timeout) = self.timeout {
+            reader = reader.with_timeout(timeout);
+        }
+        builder = builder.with_reader(reader.build());
+        for view in self.views {
+            builder = builder.with_view(view);
+        }
+        builder.build()
+    }
+}
+
+/// Interval in which measurements of instruments are sent to live metrics. Same as the post
+/// interval of live metrics.
+#[cfg(feature = "live-metrics")]
+const LIVE_METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exporter which merges measurements of selected instruments into live metrics.
+#[cfg(feature = "live-metrics")]
+struct LiveMetricsExporter {
+    collector: QuickPulseCollector,
+    instruments: HashSet<String>,
+}
+
+#[cfg(feature = "live-metrics")]
+impl std::fmt::Debug for LiveMetricsExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LiveMetricsExporter")
+            .field("instruments", &self.instruments)
+            .finish()
+    }
+}
+
+#[cfg(feature = "live-metrics")]
+impl TemporalitySelector for LiveMetricsExporter {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        DeltaTemporalitySelector.temporality(kind)
+    }
+}
+
+#[cfg(feature = "live-metrics")]
+impl AggregationSelector for LiveMetricsExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        DefaultAggregationSelector::new().aggregation(kind)
+    }
+}
+
+#[cfg(feature = "live-metrics")]
+#[async_trait]
+impl PushMetricsExporter for LiveMetricsExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> MetricsResult<()> {
+        for metric in metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope_metrics| scope_metrics.metrics.iter())
+            .filter(|metric| self.instruments.contains(metric.name.as_ref()))
+        {
+            if let Some(value) = live_metric_value(metric) {
+                self.collector.record_metric(&metric.name, value);
+            }
+        }
+        Ok(())
+    }
+
+    async fn force_flush(&self) -> MetricsResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> MetricsResult<()> {
+        Ok(())
+    }
+}
+
+/// Merges all data points of a metric into a single live metrics value.
+#[cfg(feature = "live-metrics")]
+fn live_metric_value(metric: &Metric) -> Option<LiveMetricValue> {
+    fn sum<T: ToF64Lossy>(sum: &Sum<T>) -> LiveMetricValue {
+        let total = sum
+            .data_points
+            .iter()
+            .map(|data_point| data_point.value.to_f64_lossy())
+            .sum();
+        if sum.is_monotonic && sum.temporality == Temporality::Delta {
+            LiveMetricValue::Sum(total)
+        } else {
+            LiveMetricValue::Last(total)
+        }
+    }
+    fn gauge<T: ToF64Lossy>(gauge: &Gauge<T>) -> LiveMetricValue {
+        LiveMetricValue::Last(
+            gauge
+                .data_points
+                .iter()
+                .map(|data_point| data_point.value.to_f64_lossy())
+                .sum(),
+        )
+    }
+    fn histogram<T: ToF64Lossy>(histogram: &Histogram<T>) -> LiveMetricValue {
+        LiveMetricValue::Avg {
+            sum: histogram
+                .data_points
+                .iter()
+                .map(|data_point| data_point.sum.to_f64_lossy())
+                .sum(),
+            count: histogram
+                .data_points
+                .iter()
+                .map(|data_point| data_point.count)
+                .sum(),
+        }
+    }
+    fn exponential_histogram<T: ToF64Lossy>(
+        histogram: &ExponentialHistogram<T>,
+    ) -> LiveMetricValue {
+        LiveMetricValue::Avg {
+            sum: histogram
+                .data_points
+                .iter()
+                .map(|data_point| data_point.sum.to_f64_lossy())
+                .sum(),
+            count: histogram
+                .data_points
+                .iter()
+                .map(|data_point| data_point.count as u64)
+                .sum(),
+        }
+    }
+
+    let data = metric.data.as_any();
+    let value = if let Some(data) = data.downcast_ref::<Sum<u64>>() {
+        sum(data)
+    } else if let Some(data) = data.downcast_ref::<Sum<i64>>() {
+        sum(data)
+    } else if let Some(data) = data.downcast_ref::<Sum<f64>>() {
+        sum(data)
+    } else if let Some(data) = data.downcast_ref::<Gauge<u64>>() {
+        gauge(data)
+    } else if let Some(data) = data.downcast_ref::<Gauge<i64>>() {
+        gauge(data)
+    } else if let Some(data) = data.downcast_ref::<Gauge<f64>>() {
+        gauge(data)
+    } else if let Some(data) = data.downcast_ref::<Histogram<u64>>() {
+        histogram(data)
+    } else if let Some(data) = data.downcast_ref::<Histogram<i64>>() {
+        histogram(data)
+    } else if let Some(data) = data.downcast_ref::<Histogram<f64>>() {
+        histogram(data)
+    } else if let Some(data) = data.downcast_ref::<ExponentialHistogram<u64>>() {
+        exponential_histogram(data)
+    } else if let Some(data) = data.downcast_ref::<ExponentialHistogram<i64>>() {
+        exponential_histogram(data)
+    } else if let Some(data) = data.downcast_ref::<ExponentialHistogram<f64>>() {
+        exponential_histogram(data)
+    } else {
+        return None;
+    };
+    Some(value)
+}
+
+/// Temporality expected by Application Insights: delta for counters and histograms, so every
+/// export contains only the measurements since the previous export, and cumulative for up-down
+/// counters and gauges, which are exported as their current value.
+#[derive(Debug, Default)]
+pub(crate) struct DeltaTemporalitySelector;
+
+impl TemporalitySelector for DeltaTemporalitySelector {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        match kind {
+            InstrumentKind::Counter
+            | InstrumentKind::Histogram
+            | InstrumentKind::ObservableCounter => Temporality::Delta,
+            InstrumentKind::UpDownCounter
+            | InstrumentKind::ObservableUpDownCounter
+            | InstrumentKind::Gauge
+            | InstrumentKind::ObservableGauge => Temporality::Cumulative,
+        }
+    }
+}
+
+/// Scope name, metric name and attributes of a stream.
+type StreamKey = (String, String, AttributeSet);
+
+/// Number of exports after which streams that weren't exported anymore are forgotten.
+const CUMULATIVE_SUM_STALE_EXPORTS: u64 = 10;
+
+/// Last values of monotonic sums with cumulative temporality, used to export them as deltas.
+///
+/// Streams which weren't part of the last [`CUMULATIVE_SUM_STALE_EXPORTS`] exports are evicted. If
+/// such a stream comes back, its full value is exported like for a new stream.
+#[derive(Debug, Default)]
+pub(crate) struct CumulativeSums {
+    state: Mutex<CumulativeSumsState>,
+}
+
+#[derive(Debug, Default)]
+struct CumulativeSumsState {
+    exports: u64,
+    streams: HashMap<StreamKey, CumulativeSum>,
+}
+
+#[derive(Debug)]
+struct CumulativeSum {
+    start_time: Option<SystemTime>,
+    value: f64,
+    last_export: u64,
+}
+
+impl CumulativeSums {
+    /// Returns the change since the previous value of the stream. The full value is returned for
+    /// new streams and when the stream was reset.
+    fn delta(
+        &self,
+        scope_name: &str,
+        metric_name: &str,
+        attrs: &AttributeSet,
+        start_time: Option<SystemTime>,
+        value: f64,
+    ) -> f64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let last_export = state.exports;
+        let key = (
+            scope_name.to_string(),
+            metric_name.to_string(),
+            attrs.clone(),
+        );
+        let delta = match state.streams.get(&key) {
+            Some(previous) if previous.start_time == start_time && previous.value <= value => {
+                value - previous.value
+            }
+            _ => value,
+        };
+        state.streams.insert(
+            key,
+            CumulativeSum {
+                start_time,
+                value,
+                last_export,
+            },
+        );
+        delta
+    }
+
+    /// Marks the end of an export and evicts stale streams.
+    fn finish_export(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let exports = state.exports;
+        state
+            .streams
+            .retain(|_, sum| exports - sum.last_export < CUMULATIVE_SUM_STALE_EXPORTS);
+        state.exports += 1;
+    }
+}
+
+/// Maximum number of dimensions of a metric data point. Application Insights ignores the rest.
+const MAX_METRIC_DIMENSIONS: usize = 10;
+
+/// Sanitizes metric names and dimensions to Application Insights limits and reports problems once
+/// per metric and kind of problem, so a single bad metric doesn't fail the whole export.
+#[derive(Debug, Default)]
+pub(crate) struct MetricSanitizer {
+    reported: Mutex<HashSet<(String, MetricProblem)>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MetricProblem {
+    Name,
+    Dimensions,
+}
+
+impl MetricSanitizer {
+    fn report_once(
+        &self,
+        metric_name: &str,
+        kind: MetricProblem,
+        problem: impl FnOnce() -> String,
+    ) {
+        let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+        if reported.insert((metric_name.to_string(), kind)) {
+            global::handle_error(MetricsError::Other(problem()));
+        }
+    }
+
+    /// Returns the sanitized name or `None` if the metric has no valid name.
+    fn name(&self, name: &str) -> Option<String> {
+        let sanitized = sanitize_name(name);
+        if sanitized.is_empty() {
+            self.report_once(name, MetricProblem::Name, || {
+                format!("dropped metric with invalid name {:?}", name)
+            });
+            None
+        } else {
+            if sanitized != name {
+                self.report_once(name, MetricProblem::Name, || {
+                    format!("renamed metric {:?} to {:?}", name, sanitized)
+                });
+            }
+            Some(sanitized)
+        }
+    }
+
+    /// Builds the properties of a metric envelope from the dimensions of a data point and the
+    /// attributes of its scope and resource. Names are sanitized and attributes with invalid
+    /// names are dropped. Only the first `MAX_METRIC_DIMENSIONS` data point dimensions are kept,
+    /// scope and resource attributes are always kept unless a data point dimension has the same
+    /// name.
+    fn properties<'a>(
+        &self,
+        metric_name: &str,
+        dimensions: impl Iterator<Item = (&'a Key, &'a Value)>,
+        context: impl Iterator<Item = (&'a Key, &'a Value)>,
+    ) -> Properties {
+        let mut properties = Properties::new();
+        let mut problems = Vec::new();
+        let dimensions = dimensions
+            .filter(|(k, _)| k.as_str() != METRIC_NAMESPACE)
+            .map(|kv| (kv, true));
+        let mut data_point_dimensions = 0;
+        for ((k, v), limited) in dimensions.chain(context.map(|kv| (kv, false))) {
+            let name = sanitize_name(k.as_str());
+            if name.is_empty() {
+                problems.push(format!(
+                    "dropped dimension with invalid name {:?}",
+                    k.as_str()
+                ));
+                continue;
+            }
+            let key = name.as_str().into();
+            if properties.contains_key(&key) {
+                continue;
+            }
+            if limited {
+                if data_point_dimensions >= MAX_METRIC_DIMENSIONS {
+                    problems.push(format!("dropped dimension {:?} over the limit", k.as_str()));
+                    continue;
+                }
+                data_point_dimensions += 1;
+            }
+            if name != k.as_str() {
+                problems.push(format!("renamed dimension {:?} to {:?}", k.as_str(), name));
+            }
+            properties.insert(key, v.into());
+        }
+        if !problems.is_empty() {
+            self.report_once(metric_name, MetricProblem::Dimensions, || {
+                format!("metric {:?}: {}", metric_name, problems.join(", "))
+            });
+        }
+        properties
+    }
+}
+
+/// Replaces control characters and trims whitespace.
+fn sanitize_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// Value of dimensions of series which exceed the series limit of a metric.
+const OTHER_DIMENSION_VALUE: &str = "Other";
+
+/// Number of exports after which series that weren't exported anymore don't count towards the
+/// series limit of their metric anymore.
+const METRIC_SERIES_STALE_EXPORTS: u64 = 10;
+
+/// Limits the dimensions of metrics to keep the number of series (unique combinations of
+/// dimension values) of each metric in check.
+///
+/// Series which weren't part of the last [`METRIC_SERIES_STALE_EXPORTS`] exports are evicted, so
+/// new series can take their place.
+#[derive(Debug, Default)]
+pub(crate) struct DimensionLimits {
+    pub(crate) series_limit: Option<usize>,
+    pub(crate) dropped_dimensions: HashSet<Key>,
+    state: Mutex<DimensionLimitsState>,
+}
+
+#[derive(Debug, Default)]
+struct DimensionLimitsState {
+    exports: u64,
+    /// Series and the export they were last part of, per scope and metric name.
+    series: HashMap<(String, String), HashMap<AttributeSet, u64>>,
+}
+
+impl DimensionLimits {
+    /// Removes dropped dimensions. If the metric already has the maximum number of series, the
+    /// values of all dimensions are replaced with `Other`. Returns the attributes and whether the
+    /// series was collapsed.
+    fn apply(
+        &self,
+        scope_name: &str,
+        metric_name: &str,
+        attrs: &AttributeSet,
+        diagnostics: &Diagnostics,
+    ) -> (AttributeSet, bool) {
+        let attrs = if self.dropped_dimensions.is_empty() {
+            attrs.clone()
+        } else {
+            attrs_from_iter(
+                attrs
+                    .iter()
+                    .filter(|(k, _)| !self.dropped_dimensions.contains(*k))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            )
+        };
+
+        let series_limit = match self.series_limit {
+            Some(series_limit) => series_limit,
+            None => return (attrs, false),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let exports = state.exports;
+        let metric_series = state
+            .series
+            .entry((scope_name.to_string(), metric_name.to_string()))
+            .or_default();
+        if let Some(last_export) = metric_series.get_mut(&attrs) {
+            *last_export = exports;
+            return (attrs, false);
+        }
+        if metric_series.len() < series_limit {
+            metric_series.insert(attrs.clone(), exports);
+            return (attrs, false);
+        }
+
+        diagnostics.record_collapsed_metric_series(metric_name);
+        let attrs = attrs_from_iter(attrs.iter().map(|(k, v)| {
+            if k.as_str() == METRIC_NAMESPACE {
+                (k.clone(), v.clone())
+            } else {
+                (k.clone(), Value::from(OTHER_DIMENSION_VALUE))
+            }
+        }));
+        (attrs, true)
+    }
+
+    /// Marks the end of an export and evicts stale series.
+    fn finish_export(&self) {
+        if self.series_limit.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let exports = state.exports;
+        state.series.retain(|_, metric_series| {
+            metric_series
+                .retain(|_, last_export| exports - *last_export < METRIC_SERIES_STALE_EXPORTS);
+            !metric_series.is_empty()
+        });
+        state.exports += 1;
+    }
+}
+
+/// Merges a data point of a collapsed series into the data point with the same name and
+/// attributes in `collapsed`, so each collapsed series has one data point per export. Values are
+/// added up and measurements, like values of gauges, become aggregations with their count, minimum
+/// and maximum.
+fn merge_collapsed(collapsed: &mut Vec<EnvelopeData>, data: EnvelopeData) {
+    let existing = match collapsed
+        .iter_mut()
+        .find(|existing| existing.data.name == data.data.name && existing.attrs == data.attrs)
+    {
+        Some(existing) => existing,
+        None => {
+            collapsed.push(data);
+            return;
+        }
+    };
+    let (count, min, max) = aggregation_summary(&existing.data);
+    let (other_count, other_min, other_max) = aggregation_summary(&data.data);
+    existing.time = existing.time.max(data.time);
+    existing.data.value += data.data.value;
+    existing.data.kind = Some(DataPointType::Aggregation {
+        count: match (count, other_count) {
+            (None, None) => None,
+            (count, other_count) => {
+                Some(count.unwrap_or(1).saturating_add(other_count.unwrap_or(1)))
+            }
+        },
+        min: min
+            .zip(other_min)
+            .map(|(min, other_min)| min.min(other_min)),
+        max: max
+            .zip(other_max)
+            .map(|(max, other_max)| max.max(other_max)),
+        std_dev: None,
+    });
+}
+
+/// Count, minimum and maximum of a data point. A measurement counts as an aggregation of itself.
+fn aggregation_summary(data: &DataPoint) -> (Option<i32>, Option<f64>, Option<f64>) {
+    match data.kind {
+        Some(DataPointType::Aggregation {
+            count, min, max, ..
+        }) => (count, min, max),
+        _ => (Some(1), Some(data.value), Some(data.value)),
+    }
+}
+
+fn attrs_from_iter(attrs: impl Iterator<Item = (Key, Value)>) -> AttributeSet {
+    let attrs: Vec<KeyValue> = attrs.map(|(k, v)| KeyValue::new(k, v)).collect();
+    AttributeSet::from(&attrs[..])
+}
+
+/// Maximum time `force_flush` and `shutdown` wait for exports which are still in flight.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracks in flight exports, so `force_flush` and `shutdown` can wait for them.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsExports {
+    state: Mutex<MetricsExportsState>,
+    all_done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct MetricsExportsState {
+    is_shutdown: bool,
+    in_flight: usize,
+    wakers: Vec<Waker>,
+}
+
+/// Marks an export as in flight until dropped.
+struct InFlightExport<'a>(&'a MetricsExports);
+
+impl Drop for InFlightExport<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            for waker in state.wakers.drain(..) {
+                waker.wake();
+            }
+            self.0.all_done.notify_all();
+        }
+    }
+}
+
+impl MetricsExports {
+    fn lock(&self) -> MutexGuard<'_, MetricsExportsState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns `None` if the exporter is shut down.
+    fn start(&self) -> Option<InFlightExport<'_>> {
+        let mut state = self.lock();
+        if state.is_shutdown {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlightExport(self))
+    }
+
+    /// Waits until all exports which are in flight are done. Returns `false` if they didn't
+    /// finish within the timeout.
+    ///
+    /// The exporter doesn't know the async runtime it runs on, so a thread wakes the waiting task
+    /// once the timeout expired.
+    async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut timer_started = false;
+        poll_fn(|cx| {
+            let mut state = self.lock();
+            if state.in_flight == 0 {
+                return Poll::Ready(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Poll::Ready(false);
+            }
+            state.wakers.push(cx.waker().clone());
+            if !timer_started {
+                timer_started = true;
+                let waker = cx.waker().clone();
+                let spawned = thread::Builder::new()
+                    .name("metrics-flush-timeout".into())
+                    .spawn(move || {
+                        thread::sleep(deadline - now);
+                        waker.wake();
+                    });
+                if let Err(err) = spawned {
+                    global::handle_error(MetricsError::Other(format!(
+                        "start flush timeout thread failed with {}",
+                        err
+                    )));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Rejects later exports and blocks until all exports which are in flight are done. Returns
+    /// `false` if they didn't finish within the timeout.
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let mut state = self.lock();
+        state.is_shutdown = true;
+        let (state, result) = self
+            .all_done
+            .wait_timeout_while(state, timeout, |state| state.in_flight > 0)
+            .unwrap_or_else(|e| e.into_inner());
+        drop(state);
+        !result.timed_out()
+    }
+}
 
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
 impl<C> TemporalitySelector for Exporter<C>
@@ -48,29 +753,76 @@ where
     C: HttpClient + 'static,
 {
     async fn export(&self, metrics: &mut ResourceMetrics) -> MetricsResult<()> {
+        let _in_flight = self
+            .metrics_exports
+            .start()
+            .ok_or_else(|| MetricsError::Other("exporter is shut down".into()))?;
         let client = Arc::clone(&self.client);
         let endpoint = Arc::clone(&self.endpoint);
 
         let mut envelopes = Vec::new();
         for scope_metrics in metrics.scope_metrics.iter() {
+            let scope_name = scope_metrics.scope.name.as_ref();
+            let scope_namespace = self
+                .metric_namespaces
+                .get(scope_name)
+                .map(String::as_str)
+                .unwrap_or(scope_name);
             for metric in scope_metrics.metrics.iter() {
-                let data_points = map_metric(metric);
-                for data in data_points {
+                let metric_name = match self.metric_sanitizer.name(&metric.name) {
+                    Some(metric_name) => metric_name,
+                    None => continue,
+                };
+                let mut data_points = Vec::new();
+                let mut collapsed_data_points = Vec::new();
+                for mut data in map_metric(
+                    scope_name,
+                    metric,
+                    &self.histogram_percentiles,
+                    &self.cumulative_sums,
+                ) {
+                    if metric_name != metric.name {
+                        data.data.name = data
+                            .data
+                            .name
+                            .as_ref()
+                            .replacen(metric.name.as_ref(), &metric_name, 1)
+                            .into();
+                    }
+                    let (attrs, collapsed) = self.dimension_limits.apply(
+                        scope_name,
+                        &metric.name,
+                        &data.attrs,
+                        &self.diagnostics,
+                    );
+                    data.attrs = attrs;
+                    if collapsed {
+                        merge_collapsed(&mut collapsed_data_points, data);
+                    } else {
+                        data_points.push(data);
+                    }
+                }
+                for mut data in data_points.into_iter().chain(collapsed_data_points) {
+                    data.data.ns = data
+                        .attrs
+                        .iter()
+                        .find(|(k, _)| k.as_str() == METRIC_NAMESPACE)
+                        .map(|(_, v)| v.as_str().into_owned())
+                        .or_else(|| Some(scope_namespace.to_string()))
+                        .filter(|ns| !ns.is_empty())
+                        .map(Into::into);
                     let tags =
                         get_tags_for_metric(&metrics.resource, &scope_metrics.scope, &data.attrs);
-                    let properties: Properties = metrics
-                        .resource
-                        .iter()
-                        .chain(
-                            scope_metrics
-                                .scope
-                                .attributes
-                                .iter()
-                                .map(|kv| (&kv.key, &kv.value)),
-                        )
-                        .chain(data.attrs.iter())
-                        .map(|(k, v)| (k.as_str().into(), v.into()))
-                        .collect();
+                    let properties = self.metric_sanitizer.properties(
+                        &metric.name,
+                        data.attrs.iter(),
+                        scope_metrics
+                            .scope
+                            .attributes
+                            .iter()
+                            .map(|kv| (&kv.key, &kv.value))
+                            .chain(metrics.resource.iter()),
+                    );
                     envelopes.push(Envelope {
                         name: "Microsoft.ApplicationInsights.Metric",
                         time: time_to_string(data.time).into(),
@@ -86,17 +838,36 @@ where
                 }
             }
         }
+        self.cumulative_sums.finish_export();
+        self.dimension_limits.finish_export();
 
+        finish_truncation(
+            &mut envelopes,
+            self.truncation_marker.as_deref(),
+            &self.diagnostics,
+        );
         crate::uploader::send(client.as_ref(), endpoint.as_ref(), envelopes).await?;
         Ok(())
     }
 
     async fn force_flush(&self) -> MetricsResult<()> {
-        Ok(())
+        if self.metrics_exports.wait(SHUTDOWN_TIMEOUT).await {
+            Ok(())
+        } else {
+            Err(MetricsError::Other(
+                "timed out waiting for exports to finish".into(),
+            ))
+        }
     }
 
     fn shutdown(&self) -> MetricsResult<()> {
-        Ok(())
+        if self.metrics_exports.shutdown(SHUTDOWN_TIMEOUT) {
+            Ok(())
+        } else {
+            Err(MetricsError::Other(
+                "timed out waiting for exports to finish".into(),
+            ))
+        }
     }
 }
 
@@ -128,7 +899,12 @@ impl ToF64Lossy for f64 {
     }
 }
 
-fn map_metric(metric: &Metric) -> Vec<EnvelopeData> {
+fn map_metric(
+    scope_name: &str,
+    metric: &Metric,
+    percentiles: &[f64],
+    cumulative_sums: &CumulativeSums,
+) -> Vec<EnvelopeData> {
     let data = metric.data.as_any();
     if let Some(gauge) = data.downcast_ref::<Gauge<u64>>() {
         map_gauge(metric, gauge)
@@ -137,17 +913,23 @@ fn map_metric(metric: &Metric) -> Vec<EnvelopeData> {
     } else if let Some(gauge) = data.downcast_ref::<Gauge<f64>>() {
         map_gauge(metric, gauge)
     } else if let Some(histogram) = data.downcast_ref::<Histogram<i64>>() {
-        map_histogram(metric, histogram)
+        map_histogram(metric, histogram, percentiles)
     } else if let Some(histogram) = data.downcast_ref::<Histogram<u64>>() {
-        map_histogram(metric, histogram)
+        map_histogram(metric, histogram, percentiles)
     } else if let Some(histogram) = data.downcast_ref::<Histogram<f64>>() {
-        map_histogram(metric, histogram)
+        map_histogram(metric, histogram, percentiles)
+    } else if let Some(histogram) = data.downcast_ref::<ExponentialHistogram<i64>>() {
+        map_exponential_histogram(metric, histogram, percentiles)
+    } else if let Some(histogram) = data.downcast_ref::<ExponentialHistogram<u64>>() {
+        map_exponential_histogram(metric, histogram, percentiles)
+    } else if let Some(histogram) = data.downcast_ref::<ExponentialHistogram<f64>>() {
+        map_exponential_histogram(metric, histogram, percentiles)
     } else if let Some(sum) = data.downcast_ref::<Sum<u64>>() {
-        map_sum(metric, sum)
+        map_sum(scope_name, metric, sum, cumulative_sums)
     } else if let Some(sum) = data.downcast_ref::<Sum<i64>>() {
-        map_sum(metric, sum)
+        map_sum(scope_name, metric, sum, cumulative_sums)
     } else if let Some(sum) = data.downcast_ref::<Sum<f64>>() {
-        map_sum(metric, sum)
+        map_sum(scope_name, metric, sum, cumulative_sums)
     } else {
         global::handle_error(MetricsError::Other("unknown aggregator".into()));
         Vec::new()
@@ -175,30 +957,240 @@ fn map_gauge<T: ToF64Lossy>(metric: &Metric, gauge: &Gauge<T>) -> Vec<EnvelopeDa
         .collect()
 }
 
-fn map_histogram<T: ToF64Lossy>(metric: &Metric, histogram: &Histogram<T>) -> Vec<EnvelopeData> {
+fn map_histogram<T: ToF64Lossy>(
+    metric: &Metric,
+    histogram: &Histogram<T>,
+    percentiles: &[f64],
+) -> Vec<EnvelopeData> {
     histogram
         .data_points
         .iter()
-        .map(|data_point| {
-            let time = data_point.time;
-            let data = DataPoint {
-                ns: None,
-                name: metric.name.clone().into(),
-                kind: Some(DataPointType::Aggregation {
-                    count: Some(data_point.count.try_into().unwrap_or_default()),
+        .flat_map(|data_point| {
+            let min = data_point.min.as_ref().map(ToF64Lossy::to_f64_lossy);
+            let max = data_point.max.as_ref().map(ToF64Lossy::to_f64_lossy);
+            let buckets = explicit_buckets(data_point, min, max);
+            map_histogram_data_point(
+                metric,
+                HistogramSummary {
+                    time: data_point.time,
+                    attrs: &data_point.attributes,
+                    count: data_point.count,
+                    min,
+                    max,
+                    sum: data_point.sum.to_f64_lossy(),
+                },
+                &buckets,
+                percentiles,
+            )
+        })
+        .collect()
+}
+
+fn map_exponential_histogram<T: ToF64Lossy>(
+    metric: &Metric,
+    histogram: &ExponentialHistogram<T>,
+    percentiles: &[f64],
+) -> Vec<EnvelopeData> {
+    histogram
+        .data_points
+        .iter()
+        .flat_map(|data_point| {
+            let buckets = exponential_buckets(data_point);
+            map_histogram_data_point(
+                metric,
+                HistogramSummary {
+                    time: data_point.time,
+                    attrs: &data_point.attributes,
+                    count: data_point.count.try_into().unwrap_or(u64::MAX),
                     min: data_point.min.as_ref().map(ToF64Lossy::to_f64_lossy),
                     max: data_point.max.as_ref().map(ToF64Lossy::to_f64_lossy),
-                    std_dev: None,
-                }),
-                value: data_point.sum.to_f64_lossy(),
-            };
-            let attrs = data_point.attributes.to_owned();
-            EnvelopeData { time, data, attrs }
+                    sum: data_point.sum.to_f64_lossy(),
+                },
+                &buckets,
+                percentiles,
+            )
         })
         .collect()
 }
 
-fn map_sum<T: ToF64Lossy>(metric: &Metric, sum: &Sum<T>) -> Vec<EnvelopeData> {
+struct HistogramSummary<'a> {
+    time: SystemTime,
+    attrs: &'a AttributeSet,
+    count: u64,
+    min: Option<f64>,
+    max: Option<f64>,
+    sum: f64,
+}
+
+/// Map a histogram data point to one aggregation and one measurement per percentile.
+fn map_histogram_data_point(
+    metric: &Metric,
+    summary: HistogramSummary,
+    buckets: &[Bucket],
+    percentiles: &[f64],
+) -> Vec<EnvelopeData> {
+    let mut result = Vec::with_capacity(1 + percentiles.len());
+    result.push(EnvelopeData {
+        time: summary.time,
+        data: DataPoint {
+            ns: None,
+            name: metric.name.clone().into(),
+            kind: Some(DataPointType::Aggregation {
+                count: Some(summary.count.try_into().unwrap_or_default()),
+                min: summary.min,
+                max: summary.max,
+                std_dev: estimate_std_dev(buckets, summary.count, summary.sum),
+            }),
+            value: summary.sum,
+        },
+        attrs: summary.attrs.to_owned(),
+    });
+
+    for &percentile in percentiles {
+        if let Some(value) = estimate_percentile(buckets, summary.count, percentile)
+            .map(|value| clamp_to_min_max(value, summary.min, summary.max))
+        {
+            result.push(EnvelopeData {
+                time: summary.time,
+                data: DataPoint {
+                    ns: None,
+                    name: format!("{}.p{}", metric.name, percentile).into(),
+                    kind: Some(DataPointType::Measurement),
+                    value,
+                },
+                attrs: summary.attrs.to_owned(),
+            });
+        }
+    }
+
+    result
+}
+
+/// Histogram bucket. All measurements in it are between the lower and upper boundary.
+#[derive(Debug, PartialEq)]
+struct Bucket {
+    lower: f64,
+    upper: f64,
+    count: u64,
+}
+
+/// Buckets of an explicit bucket histogram in ascending order. The infinite boundaries of the
+/// first and last bucket are replaced with min and max if available, or else with the finite
+/// boundary of the bucket.
+fn explicit_buckets<T>(
+    data_point: &HistogramDataPoint<T>,
+    min: Option<f64>,
+    max: Option<f64>,
+) -> Vec<Bucket> {
+    let bounds = &data_point.bounds;
+    data_point
+        .bucket_counts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &count)| {
+            let lower = i.checked_sub(1).and_then(|i| bounds.get(i).copied());
+            let upper = bounds.get(i).copied();
+            let lower = lower.or(min).or(upper)?;
+            let upper = upper.or(max).unwrap_or(lower);
+            Some(Bucket {
+                lower,
+                upper,
+                count,
+            })
+        })
+        .collect()
+}
+
+/// Buckets of an exponential histogram in ascending order.
+fn exponential_buckets<T>(data_point: &ExponentialHistogramDataPoint<T>) -> Vec<Bucket> {
+    let base = 2f64.powf(2f64.powi(-i32::from(data_point.scale)));
+    let boundaries = |bucket: &ExponentialBucket| {
+        (bucket.offset..)
+            .zip(bucket.counts.iter())
+            .map(|(index, &count)| (base.powi(index), base.powi(index + 1), count))
+            .collect::<Vec<_>>()
+    };
+
+    let mut buckets: Vec<Bucket> = boundaries(&data_point.negative_bucket)
+        .into_iter()
+        .rev()
+        .map(|(lower, upper, count)| Bucket {
+            lower: -upper,
+            upper: -lower,
+            count,
+        })
+        .collect();
+    buckets.push(Bucket {
+        lower: 0.,
+        upper: 0.,
+        count: data_point.zero_count,
+    });
+    buckets.extend(boundaries(&data_point.positive_bucket).into_iter().map(
+        |(lower, upper, count)| Bucket {
+            lower,
+            upper,
+            count,
+        },
+    ));
+    buckets
+}
+
+/// Estimate the standard deviation by assuming all measurements in a bucket are at the midpoint
+/// of the bucket.
+fn estimate_std_dev(buckets: &[Bucket], count: u64, sum: f64) -> Option<f64> {
+    if count == 0 {
+        return None;
+    }
+
+    let count = count as f64;
+    let mean = sum / count;
+    let sum_of_squares: f64 = buckets
+        .iter()
+        .map(|bucket| {
+            let midpoint = (bucket.lower + bucket.upper) / 2.;
+            bucket.count as f64 * (midpoint - mean).powi(2)
+        })
+        .sum();
+
+    Some((sum_of_squares / count).sqrt()).filter(|std_dev| std_dev.is_finite())
+}
+
+/// Estimate the percentile (0-100) by assuming measurements are evenly distributed within each
+/// bucket.
+fn estimate_percentile(buckets: &[Bucket], count: u64, percentile: f64) -> Option<f64> {
+    if count == 0 {
+        return None;
+    }
+
+    let rank = percentile / 100. * count as f64;
+    let mut cumulative_count = 0.;
+    for bucket in buckets.iter().filter(|bucket| bucket.count > 0) {
+        let bucket_count = bucket.count as f64;
+        if cumulative_count + bucket_count >= rank {
+            let fraction = (rank - cumulative_count) / bucket_count;
+            return Some(bucket.lower + (bucket.upper - bucket.lower) * fraction);
+        }
+        cumulative_count += bucket_count;
+    }
+
+    buckets
+        .iter()
+        .rev()
+        .find(|bucket| bucket.count > 0)
+        .map(|bucket| bucket.upper)
+}
+
+fn clamp_to_min_max(value: f64, min: Option<f64>, max: Option<f64>) -> f64 {
+    let value = min.map_or(value, |min| value.max(min));
+    max.map_or(value, |max| value.min(max))
+}
+
+fn map_sum<T: ToF64Lossy>(
+    scope_name: &str,
+    metric: &Metric,
+    sum: &Sum<T>,
+    cumulative_sums: &CumulativeSums,
+) -> Vec<EnvelopeData> {
     sum.data_points
         .iter()
         .map(|data_point| {
@@ -206,19 +1198,467 @@ fn map_sum<T: ToF64Lossy>(metric: &Metric, sum: &Sum<T>) -> Vec<EnvelopeData> {
                 .time
                 .or(data_point.start_time)
                 .unwrap_or_else(SystemTime::now);
-            let data = DataPoint {
-                ns: None,
-                name: metric.name.clone().into(),
-                kind: Some(DataPointType::Aggregation {
-                    count: None,
-                    min: None,
-                    max: None,
-                    std_dev: None,
-                }),
-                value: data_point.value.to_f64_lossy(),
+            let value = data_point.value.to_f64_lossy();
+            let data = if !sum.is_monotonic {
+                // Up-down counters can go down, so their sum is the current value like a gauge.
+                DataPoint {
+                    ns: None,
+                    name: metric.name.clone().into(),
+                    kind: Some(DataPointType::Measurement),
+                    value,
+                }
+            } else {
+                let value = match sum.temporality {
+                    Temporality::Cumulative => cumulative_sums.delta(
+                        scope_name,
+                        &metric.name,
+                        &data_point.attributes,
+                        data_point.start_time,
+                        value,
+                    ),
+                    _ => value,
+                };
+                DataPoint {
+                    ns: None,
+                    name: metric.name.clone().into(),
+                    kind: Some(DataPointType::Aggregation {
+                        count: None,
+                        min: None,
+                        max: None,
+                        std_dev: None,
+                    }),
+                    value,
+                }
             };
             let attrs = data_point.attributes.to_owned();
             EnvelopeData { time, data, attrs }
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn exponential_histogram_data_point(
+        values: &[f64],
+        scale: i8,
+        positive_bucket: ExponentialBucket,
+        negative_bucket: ExponentialBucket,
+    ) -> ExponentialHistogramDataPoint<f64> {
+        ExponentialHistogramDataPoint {
+            attributes: AttributeSet::default(),
+            start_time: SystemTime::UNIX_EPOCH,
+            time: SystemTime::UNIX_EPOCH,
+            count: values.len(),
+            min: values.iter().copied().reduce(f64::min),
+            max: values.iter().copied().reduce(f64::max),
+            sum: values.iter().sum(),
+            scale,
+            zero_count: values.iter().filter(|v| **v == 0.).count() as u64,
+            positive_bucket,
+            negative_bucket,
+            zero_threshold: 0.,
+            exemplars: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn exponential_histogram_std_dev_empty() {
+        let data_point = exponential_histogram_data_point(
+            &[],
+            0,
+            ExponentialBucket {
+                offset: 0,
+                counts: Vec::new(),
+            },
+            ExponentialBucket {
+                offset: 0,
+                counts: Vec::new(),
+            },
+        );
+        assert_eq!(
+            None,
+            estimate_std_dev(&exponential_buckets(&data_point), 0, 0.)
+        );
+    }
+
+    #[test]
+    fn exponential_histogram_std_dev_estimate() {
+        // Scale 0 means base 2. The values 1.5 and 3 are in the buckets (1, 2] and (2, 4] with the
+        // midpoints 1.5 and 3. Their standard deviation is 0.75.
+        let data_point = exponential_histogram_data_point(
+            &[1.5, 3.],
+            0,
+            ExponentialBucket {
+                offset: 0,
+                counts: vec![1, 1],
+            },
+            ExponentialBucket {
+                offset: 0,
+                counts: Vec::new(),
+            },
+        );
+        assert_eq!(
+            Some(0.75),
+            estimate_std_dev(&exponential_buckets(&data_point), 2, 4.5)
+        );
+
+        // Negative buckets mirror the positive ones.
+        let data_point = exponential_histogram_data_point(
+            &[-1.5, 1.5],
+            0,
+            ExponentialBucket {
+                offset: 0,
+                counts: vec![1],
+            },
+            ExponentialBucket {
+                offset: 0,
+                counts: vec![1],
+            },
+        );
+        assert_eq!(
+            Some(1.5),
+            estimate_std_dev(&exponential_buckets(&data_point), 2, 0.)
+        );
+    }
+
+    fn histogram_data_point(
+        bounds: Vec<f64>,
+        bucket_counts: Vec<u64>,
+        min: f64,
+        max: f64,
+        sum: f64,
+    ) -> HistogramDataPoint<f64> {
+        HistogramDataPoint {
+            attributes: AttributeSet::default(),
+            start_time: SystemTime::UNIX_EPOCH,
+            time: SystemTime::UNIX_EPOCH,
+            count: bucket_counts.iter().sum(),
+            bounds,
+            bucket_counts,
+            min: Some(min),
+            max: Some(max),
+            sum,
+            exemplars: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn explicit_buckets_replace_infinite_bounds() {
+        let data_point = histogram_data_point(vec![10., 20.], vec![1, 0, 1], 5., 30., 35.);
+        assert_eq!(
+            vec![
+                Bucket {
+                    lower: 5.,
+                    upper: 10.,
+                    count: 1
+                },
+                Bucket {
+                    lower: 10.,
+                    upper: 20.,
+                    count: 0
+                },
+                Bucket {
+                    lower: 20.,
+                    upper: 30.,
+                    count: 1
+                },
+            ],
+            explicit_buckets(&data_point, Some(5.), Some(30.))
+        );
+    }
+
+    #[test]
+    fn explicit_histogram_std_dev() {
+        // Midpoints 7.5 and 25 with mean 16.25.
+        let data_point = histogram_data_point(vec![10., 20.], vec![1, 0, 1], 5., 30., 32.5);
+        let buckets = explicit_buckets(&data_point, Some(5.), Some(30.));
+        assert_eq!(Some(8.75), estimate_std_dev(&buckets, 2, 32.5));
+    }
+
+    #[test]
+    fn explicit_histogram_percentiles() {
+        // 100 measurements evenly distributed between 0 and 100.
+        let data_point =
+            histogram_data_point(vec![25., 50., 75.], vec![25, 25, 25, 25], 0., 100., 5000.);
+        let buckets = explicit_buckets(&data_point, Some(0.), Some(100.));
+        assert_eq!(Some(50.), estimate_percentile(&buckets, 100, 50.));
+        assert_eq!(Some(95.), estimate_percentile(&buckets, 100, 95.));
+        assert_eq!(Some(99.), estimate_percentile(&buckets, 100, 99.));
+        assert_eq!(Some(100.), estimate_percentile(&buckets, 100, 100.));
+        assert_eq!(None, estimate_percentile(&buckets, 0, 50.));
+    }
+
+    #[test]
+    fn delta_temporality_for_counters_and_histograms() {
+        let selector = DeltaTemporalitySelector;
+        assert_eq!(
+            Temporality::Delta,
+            selector.temporality(InstrumentKind::Counter)
+        );
+        assert_eq!(
+            Temporality::Delta,
+            selector.temporality(InstrumentKind::Histogram)
+        );
+        assert_eq!(
+            Temporality::Cumulative,
+            selector.temporality(InstrumentKind::UpDownCounter)
+        );
+    }
+
+    #[test]
+    fn cumulative_sums_to_delta() {
+        let sums = CumulativeSums::default();
+        let attrs = AttributeSet::from(&[KeyValue::new("route", "/hello")][..]);
+        let start_time = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(5., sums.delta("scope", "requests", &attrs, start_time, 5.));
+        assert_eq!(3., sums.delta("scope", "requests", &attrs, start_time, 8.));
+        assert_eq!(0., sums.delta("scope", "requests", &attrs, start_time, 8.));
+        // Other streams are tracked separately.
+        assert_eq!(
+            2.,
+            sums.delta(
+                "scope",
+                "requests",
+                &AttributeSet::default(),
+                start_time,
+                2.
+            )
+        );
+        // Reset
+        assert_eq!(4., sums.delta("scope", "requests", &attrs, start_time, 4.));
+        assert_eq!(
+            1.,
+            sums.delta("scope", "requests", &attrs, Some(SystemTime::now()), 1.)
+        );
+    }
+
+    #[test]
+    fn cumulative_sums_evict_stale_streams() {
+        let sums = CumulativeSums::default();
+        let attrs = AttributeSet::from(&[KeyValue::new("route", "/hello")][..]);
+        let start_time = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(5., sums.delta("scope", "requests", &attrs, start_time, 5.));
+        assert_eq!(1., sums.delta("scope", "active", &attrs, start_time, 1.));
+        sums.finish_export();
+        for _ in 0..CUMULATIVE_SUM_STALE_EXPORTS {
+            assert_eq!(0., sums.delta("scope", "requests", &attrs, start_time, 5.));
+            sums.finish_export();
+        }
+        assert_eq!(1, sums.state.lock().unwrap().streams.len());
+        // A stream which comes back is exported in full.
+        assert_eq!(1., sums.delta("scope", "active", &attrs, start_time, 1.));
+        assert_eq!(0., sums.delta("scope", "requests", &attrs, start_time, 5.));
+    }
+
+    #[test]
+    fn dimension_limits_collapse_series_over_limit() {
+        let limits = DimensionLimits {
+            series_limit: Some(2),
+            ..DimensionLimits::default()
+        };
+        let diagnostics = Diagnostics::new();
+        let attrs = |user: &str| {
+            AttributeSet::from(
+                &[
+                    KeyValue::new("user.id", user.to_string()),
+                    KeyValue::new(METRIC_NAMESPACE, "custom"),
+                ][..],
+            )
+        };
+
+        for user in ["a", "b", "a"] {
+            assert_eq!(
+                (attrs(user), false),
+                limits.apply("scope", "requests", &attrs(user), &diagnostics)
+            );
+        }
+        assert_eq!(
+            (
+                AttributeSet::from(
+                    &[
+                        KeyValue::new("user.id", "Other"),
+                        KeyValue::new(METRIC_NAMESPACE, "custom"),
+                    ][..]
+                ),
+                true
+            ),
+            limits.apply("scope", "requests", &attrs("c"), &diagnostics)
+        );
+        // Other metrics have their own limit.
+        assert_eq!(
+            (attrs("c"), false),
+            limits.apply("scope", "errors", &attrs("c"), &diagnostics)
+        );
+        assert_eq!(
+            BTreeMap::from([("requests".to_string(), 1)]),
+            diagnostics.collapsed_metric_series()
+        );
+    }
+
+    #[test]
+    fn dimension_limits_drop_dimensions() {
+        let limits = DimensionLimits {
+            dropped_dimensions: HashSet::from([Key::from_static_str("url.full")]),
+            ..DimensionLimits::default()
+        };
+        let attrs = AttributeSet::from(
+            &[
+                KeyValue::new("url.full", "https://example.com/?user=a"),
+                KeyValue::new("http.request.method", "GET"),
+            ][..],
+        );
+        assert_eq!(
+            (
+                AttributeSet::from(&[KeyValue::new("http.request.method", "GET")][..]),
+                false
+            ),
+            limits.apply("scope", "requests", &attrs, &Diagnostics::new())
+        );
+    }
+
+    #[test]
+    fn dimension_limits_evict_stale_series() {
+        let limits = DimensionLimits {
+            series_limit: Some(1),
+            ..DimensionLimits::default()
+        };
+        let diagnostics = Diagnostics::new();
+        let is_collapsed = |user: &str| {
+            let attrs = AttributeSet::from(&[KeyValue::new("user.id", user.to_string())][..]);
+            limits.apply("scope", "requests", &attrs, &diagnostics).1
+        };
+
+        assert!(!is_collapsed("a"));
+        limits.finish_export();
+        for _ in 0..METRIC_SERIES_STALE_EXPORTS {
+            assert!(is_collapsed("b"));
+            limits.finish_export();
+        }
+        // Series "a" wasn't exported for long enough to make room for a new series.
+        assert!(!is_collapsed("b"));
+        assert!(is_collapsed("a"));
+    }
+
+    #[test]
+    fn merge_data_points_of_collapsed_series() {
+        let point = |value: f64, kind: DataPointType| EnvelopeData {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(value as u64),
+            data: DataPoint {
+                ns: None,
+                name: "active".into(),
+                kind: Some(kind),
+                value,
+            },
+            attrs: AttributeSet::from(&[KeyValue::new("user.id", "Other")][..]),
+        };
+        let mut collapsed = Vec::new();
+        merge_collapsed(&mut collapsed, point(3., DataPointType::Measurement));
+        merge_collapsed(&mut collapsed, point(1., DataPointType::Measurement));
+        merge_collapsed(
+            &mut collapsed,
+            point(
+                6.,
+                DataPointType::Aggregation {
+                    count: Some(2),
+                    min: Some(2.),
+                    max: Some(4.),
+                    std_dev: Some(1.),
+                },
+            ),
+        );
+
+        assert_eq!(1, collapsed.len());
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(6),
+            collapsed[0].time
+        );
+        assert_eq!(10., collapsed[0].data.value);
+        assert_eq!(
+            (Some(4), Some(1.), Some(4.)),
+            aggregation_summary(&collapsed[0].data)
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_exports_in_flight_until_timeout() {
+        let exports = MetricsExports::default();
+        let in_flight = exports.start().expect("exports are not shut down");
+        assert!(!exports.wait(Duration::from_millis(10)).await);
+        drop(in_flight);
+        assert!(exports.wait(Duration::from_millis(10)).await);
+    }
+
+    #[test]
+    fn sanitize_metric_names() {
+        let sanitizer = MetricSanitizer::default();
+        assert_eq!(Some("requests".into()), sanitizer.name("requests"));
+        assert_eq!(Some("req_uests".into()), sanitizer.name(" req\nuests "));
+        assert_eq!(None, sanitizer.name("  "));
+    }
+
+    #[test]
+    fn sanitize_metric_dimensions() {
+        let sanitizer = MetricSanitizer::default();
+        let attrs: Vec<_> = (0..11)
+            .map(|i| KeyValue::new(format!("dim{:02}", i), i))
+            .chain([
+                KeyValue::new(" ", "empty"),
+                KeyValue::new(" spaced ", "trimmed"),
+                KeyValue::new(METRIC_NAMESPACE, "custom"),
+            ])
+            .collect();
+        let resource = [
+            KeyValue::new("dim00", "resource"),
+            KeyValue::new("service.name", "server"),
+            KeyValue::new("host.name", "host"),
+        ];
+        let properties = sanitizer.properties(
+            "requests",
+            attrs.iter().map(|kv| (&kv.key, &kv.value)),
+            resource.iter().map(|kv| (&kv.key, &kv.value)),
+        );
+        let properties: Vec<_> = properties
+            .iter()
+            .map(|(k, v)| (k.as_ref(), v.as_ref()))
+            .collect();
+        // Data point dimensions beyond the first 10, the dimension with an empty name and the
+        // namespace are dropped. Data point dimensions win over resource attributes, which don't
+        // count towards the limit.
+        assert_eq!(
+            vec![
+                ("dim00", "0"),
+                ("dim01", "1"),
+                ("dim02", "2"),
+                ("dim03", "3"),
+                ("dim04", "4"),
+                ("dim05", "5"),
+                ("dim06", "6"),
+                ("dim07", "7"),
+                ("dim08", "8"),
+                ("dim09", "9"),
+                ("host.name", "host"),
+                ("service.name", "server"),
+            ],
+            properties
+        );
+    }
+
+    #[test]
+    fn report_metric_problems_once_per_kind() {
+        let sanitizer = MetricSanitizer::default();
+        let reported = |sanitizer: &MetricSanitizer| sanitizer.reported.lock().unwrap().len();
+        sanitizer.name(" requests");
+        sanitizer.name(" requests");
+        assert_eq!(1, reported(&sanitizer));
+        let attrs = [KeyValue::new(" ", "empty")];
+        sanitizer.properties(
+            " requests",
+            attrs.iter().map(|kv| (&kv.key, &kv.value)),
+            std::iter::empty(),
+        );
+        assert_eq!(2, reported(&sanitizer));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::models::truncate_to_char_boundary;
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use std::collections::BTreeMap;
@@ -14,32 +15,64 @@ impl ContextTagKey {
     }
 }
 
-#[derive(Debug, Serialize)]
-pub(crate) struct Tags(BTreeMap<&'static str, String>);
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Tags {
+    #[serde(flatten)]
+    values: BTreeMap<&'static str, String>,
+    /// Keys of truncated values and their max length.
+    #[serde(skip)]
+    truncated: BTreeMap<&'static str, usize>,
+}
 
 impl Tags {
     pub(crate) fn new() -> Self {
-        Self(BTreeMap::new())
+        Self {
+            values: BTreeMap::new(),
+            truncated: BTreeMap::new(),
+        }
     }
 
     pub(crate) fn insert(&mut self, key: ContextTagKey, mut value: String) -> Option<String> {
-        value.truncate(key.max_len);
-        self.0.insert(key.key, value)
+        if truncate_to_char_boundary(&mut value, key.max_len) {
+            self.truncated.insert(key.key, key.max_len);
+        } else {
+            self.truncated.remove(key.key);
+        }
+        self.values.insert(key.key, value)
     }
 
-    #[cfg(feature = "live-metrics")]
+    #[cfg(any(feature = "live-metrics", feature = "metrics"))]
     pub(crate) fn remove(&mut self, key: ContextTagKey) -> Option<String> {
-        self.0.remove(key.key)
+        self.truncated.remove(key.key);
+        self.values.remove(key.key)
     }
 
-    #[cfg(test)]
     pub(crate) fn get(&self, key: &ContextTagKey) -> Option<&String> {
-        self.0.get(key.key)
+        self.values.get(key.key)
     }
 
     #[cfg(feature = "metrics")]
     pub(crate) fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.values.is_empty()
+    }
+
+    /// Replace the end of truncated values with the given marker and call `report` with the key of
+    /// each truncated value.
+    pub(crate) fn finish_truncation(
+        &mut self,
+        marker: Option<&str>,
+        mut report: impl FnMut(&'static str),
+    ) {
+        for (key, max_len) in std::mem::take(&mut self.truncated) {
+            if let (Some(value), Some(marker)) = (
+                self.values.get_mut(key),
+                marker.filter(|marker| marker.len() <= max_len),
+            ) {
+                truncate_to_char_boundary(value, max_len - marker.len());
+                value.push_str(marker);
+            }
+            report(key);
+        }
     }
 }
 
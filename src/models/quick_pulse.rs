@@ -1,9 +1,10 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct QuickPulseMetric {
-    pub(crate) name: &'static str,
+    pub(crate) name: Cow<'static, str>,
     pub(crate) value: f64,
     pub(crate) weight: usize,
 }
@@ -19,8 +20,155 @@ pub(crate) struct QuickPulseEnvelope {
     pub(crate) machine_name: String,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub(crate) metrics: Vec<QuickPulseMetric>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) documents: Vec<QuickPulseDocument>,
     pub(crate) stream_id: String,
     pub(crate) timestamp: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) version: Option<String>,
 }
+
+/// Sample telemetry item shown in the sample telemetry pane of Live Metrics.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct QuickPulseDocument {
+    #[serde(rename = "__type")]
+    pub(crate) type_: &'static str,
+    pub(crate) version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) operation_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) properties: Vec<QuickPulseDocumentProperty>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) document_stream_ids: Vec<String>,
+    #[serde(flatten)]
+    pub(crate) data: QuickPulseDocumentData,
+}
+
+impl QuickPulseDocument {
+    pub(crate) fn new(
+        operation_id: Option<String>,
+        properties: Vec<QuickPulseDocumentProperty>,
+        data: QuickPulseDocumentData,
+    ) -> Self {
+        Self {
+            type_: data.type_name(),
+            version: "1.0",
+            operation_id,
+            properties,
+            document_stream_ids: Vec::new(),
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "DocumentType")]
+pub(crate) enum QuickPulseDocumentData {
+    #[serde(rename_all = "PascalCase")]
+    Request {
+        name: String,
+        success: bool,
+        duration: String,
+        response_code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        operation_name: Option<String>,
+    },
+    #[serde(rename_all = "PascalCase")]
+    RemoteDependency {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        success: Option<bool>,
+        duration: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result_code: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        command_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dependency_type_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        operation_name: Option<String>,
+    },
+    #[serde(rename_all = "PascalCase")]
+    Exception {
+        exception: String,
+        exception_message: String,
+        exception_type: String,
+    },
+    #[serde(rename_all = "PascalCase")]
+    Trace {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        severity_level: Option<&'static str>,
+    },
+}
+
+impl QuickPulseDocumentData {
+    fn type_name(&self) -> &'static str {
+        match self {
+            QuickPulseDocumentData::Request { .. } => "RequestTelemetryDocument",
+            QuickPulseDocumentData::RemoteDependency { .. } => "DependencyTelemetryDocument",
+            QuickPulseDocumentData::Exception { .. } => "ExceptionTelemetryDocument",
+            QuickPulseDocumentData::Trace { .. } => "TraceTelemetryDocument",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct QuickPulseDocumentProperty {
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+/// Collection configuration sent by Live Metrics, which describes the custom charts and sample
+/// telemetry filters configured in the portal.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct CollectionConfigurationInfo {
+    #[serde(rename = "ETag")]
+    pub(crate) etag: String,
+    pub(crate) metrics: Vec<DerivedMetricInfo>,
+    pub(crate) document_streams: Vec<DocumentStreamInfo>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct DerivedMetricInfo {
+    pub(crate) id: String,
+    pub(crate) telemetry_type: String,
+    pub(crate) filter_groups: Vec<FilterConjunctionGroupInfo>,
+    pub(crate) projection: String,
+    pub(crate) aggregation: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct DocumentStreamInfo {
+    pub(crate) id: String,
+    pub(crate) document_filter_groups: Vec<DocumentFilterConjunctionGroupInfo>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct DocumentFilterConjunctionGroupInfo {
+    pub(crate) telemetry_type: String,
+    pub(crate) filters: FilterConjunctionGroupInfo,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct FilterConjunctionGroupInfo {
+    pub(crate) filters: Vec<FilterInfo>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct FilterInfo {
+    pub(crate) field_name: String,
+    pub(crate) predicate: String,
+    pub(crate) comparand: String,
+}
@@ -1,19 +1,51 @@
-use serde::Serialize;
-use std::{borrow::Cow, collections::BTreeMap};
+use crate::{
+    models::{Data, Envelope},
+    Diagnostics,
+};
+use opentelemetry::{Array, Value};
+use serde::{Serialize, Serializer};
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeMap};
 
-#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
-pub(crate) struct LimitedLenString<const N: usize>(String);
+#[derive(Debug)]
+pub(crate) struct LimitedLenString<const N: usize> {
+    value: String,
+    truncated: bool,
+}
+
+impl<const N: usize> LimitedLenString<N> {
+    /// Replace the end of a truncated value with the given marker, so readers can tell the value
+    /// is incomplete. Returns whether the value was truncated.
+    fn finish_truncation(&mut self, marker: Option<&str>) -> bool {
+        if !self.truncated {
+            return false;
+        }
+
+        if let Some(marker) = marker.filter(|marker| marker.len() <= N) {
+            truncate_to_char_boundary(&mut self.value, N - marker.len());
+            self.value.push_str(marker);
+        }
+
+        true
+    }
+}
 
 impl<const N: usize> From<&str> for LimitedLenString<N> {
     fn from(s: &str) -> Self {
-        Self(String::from(&s[0..std::cmp::min(s.len(), N)]))
+        let len = floor_char_boundary(s, N);
+        Self {
+            value: String::from(&s[0..len]),
+            truncated: len < s.len(),
+        }
     }
 }
 
 impl<const N: usize> From<String> for LimitedLenString<N> {
     fn from(mut s: String) -> Self {
-        s.truncate(N);
-        Self(s)
+        let truncated = truncate_to_char_boundary(&mut s, N);
+        Self {
+            value: s,
+            truncated,
+        }
     }
 }
 
@@ -32,17 +64,326 @@ impl<const N: usize> From<&opentelemetry::Key> for LimitedLenString<N> {
     }
 }
 
-impl<const N: usize> From<&opentelemetry::Value> for LimitedLenString<N> {
-    fn from(v: &opentelemetry::Value) -> Self {
-        v.as_str().into()
+impl<const N: usize> From<&Value> for LimitedLenString<N> {
+    fn from(v: &Value) -> Self {
+        match v {
+            Value::Array(array) => array_to_json(array).into(),
+            _ => v.as_str().into(),
+        }
+    }
+}
+
+#[cfg(feature = "live-metrics")]
+impl<const N: usize> From<LimitedLenString<N>> for String {
+    fn from(s: LimitedLenString<N>) -> Self {
+        s.value
     }
 }
 
 impl<const N: usize> AsRef<str> for LimitedLenString<N> {
     #[inline]
     fn as_ref(&self) -> &str {
-        self.0.as_ref()
+        self.value.as_ref()
+    }
+}
+
+impl<const N: usize> PartialEq for LimitedLenString<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<const N: usize> Eq for LimitedLenString<N> {}
+
+impl<const N: usize> PartialOrd for LimitedLenString<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for LimitedLenString<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl<const N: usize> Serialize for LimitedLenString<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.value)
     }
 }
 
 pub(crate) type Properties = BTreeMap<LimitedLenString<150>, LimitedLenString<8192>>;
+
+/// Largest index less than or equal to `max_len`, which does not split a character.
+fn floor_char_boundary(s: &str, max_len: usize) -> usize {
+    if s.len() <= max_len {
+        return s.len();
+    }
+
+    let mut len = max_len;
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+/// Truncate the string to at most `max_len` bytes without splitting a character. Returns whether
+/// the string was truncated.
+pub(crate) fn truncate_to_char_boundary(s: &mut String, max_len: usize) -> bool {
+    let len = floor_char_boundary(s, max_len);
+    let truncated = len < s.len();
+    s.truncate(len);
+    truncated
+}
+
+/// Serialize array values as JSON, so they can be queried with `parse_json()` in Application
+/// Insights. The `Display` implementation of `Array` does not escape strings.
+fn array_to_json(array: &Array) -> String {
+    match array {
+        Array::Bool(values) => serde_json::to_string(values),
+        Array::I64(values) => serde_json::to_string(values),
+        Array::F64(values) => serde_json::to_string(values),
+        Array::String(values) => {
+            serde_json::to_string(&values.iter().map(|v| v.as_str()).collect::<Vec<_>>())
+        }
+    }
+    .unwrap_or_else(|_| array.to_string())
+}
+
+/// Apply the truncation marker to all values in the envelopes, which were cut to the Application
+/// Insights length limits, and count them per field.
+pub(crate) fn finish_truncation(
+    envelopes: &mut [Envelope],
+    marker: Option<&str>,
+    diagnostics: &Diagnostics,
+) {
+    let report = |field: &'static str, truncated: bool| {
+        if truncated {
+            diagnostics.record_truncated_field(field);
+        }
+    };
+
+    for envelope in envelopes.iter_mut() {
+        report("time", envelope.time.finish_truncation(marker));
+        if let Some(i_key) = envelope.i_key.as_mut() {
+            report("iKey", i_key.finish_truncation(marker));
+        }
+        if let Some(tags) = envelope.tags.as_mut() {
+            tags.finish_truncation(marker, |key| report(key, true));
+        }
+        match envelope.data.as_mut() {
+            Some(Data::Event(data)) => {
+                report("EventData.name", data.name.finish_truncation(marker));
+                report(
+                    "EventData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            Some(Data::Exception(data)) => {
+                for exception in data.exceptions.iter_mut() {
+                    report(
+                        "ExceptionData.typeName",
+                        exception.type_name.finish_truncation(marker),
+                    );
+                    report(
+                        "ExceptionData.message",
+                        exception.message.finish_truncation(marker),
+                    );
+                    if let Some(stack) = exception.stack.as_mut() {
+                        report("ExceptionData.stack", stack.finish_truncation(marker));
+                    }
+                }
+                report(
+                    "ExceptionData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            Some(Data::Message(data)) => {
+                report(
+                    "MessageData.message",
+                    data.message.finish_truncation(marker),
+                );
+                report(
+                    "MessageData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            #[cfg(feature = "metrics")]
+            Some(Data::Metric(data)) => {
+                for data_point in data.metrics.iter_mut() {
+                    if let Some(ns) = data_point.ns.as_mut() {
+                        report("MetricData.ns", ns.finish_truncation(marker));
+                    }
+                    report("MetricData.name", data_point.name.finish_truncation(marker));
+                }
+                report(
+                    "MetricData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            Some(Data::RemoteDependency(data)) => {
+                report(
+                    "RemoteDependencyData.name",
+                    data.name.finish_truncation(marker),
+                );
+                for (field, value) in [
+                    ("RemoteDependencyData.resultCode", data.result_code.as_mut()),
+                    ("RemoteDependencyData.target", data.target.as_mut()),
+                    ("RemoteDependencyData.type", data.type_.as_mut()),
+                ] {
+                    if let Some(value) = value {
+                        report(field, value.finish_truncation(marker));
+                    }
+                }
+                if let Some(value) = data.data.as_mut() {
+                    report("RemoteDependencyData.data", value.finish_truncation(marker));
+                }
+                report(
+                    "RemoteDependencyData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            Some(Data::Request(data)) => {
+                for (field, value) in [
+                    ("RequestData.source", data.source.as_mut()),
+                    ("RequestData.name", data.name.as_mut()),
+                    ("RequestData.responseCode", Some(&mut data.response_code)),
+                ] {
+                    if let Some(value) = value {
+                        report(field, value.finish_truncation(marker));
+                    }
+                }
+                if let Some(url) = data.url.as_mut() {
+                    report("RequestData.url", url.finish_truncation(marker));
+                }
+                report(
+                    "RequestData.properties",
+                    finish_properties_truncation(&mut data.properties, marker),
+                );
+            }
+            None => {}
+        }
+    }
+}
+
+/// Returns whether any key or value in the properties was truncated.
+fn finish_properties_truncation(properties: &mut Option<Properties>, marker: Option<&str>) -> bool {
+    let properties = match properties.as_mut() {
+        Some(properties) => properties,
+        None => return false,
+    };
+
+    let mut truncated = false;
+    for value in properties.values_mut() {
+        truncated |= value.finish_truncation(marker);
+    }
+
+    if properties.keys().any(|key| key.truncated) {
+        truncated = true;
+        *properties = std::mem::take(properties)
+            .into_iter()
+            .map(|(mut key, value)| {
+                key.finish_truncation(marker);
+                (key, value)
+            })
+            .collect();
+    }
+
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        context_tag_keys::{Tags, DEVICE_LOCALE},
+        MessageData,
+    };
+    use opentelemetry::StringValue;
+    use test_case::test_case;
+
+    #[test_case(Value::Bool(true), "true" ; "bool")]
+    #[test_case(Value::I64(42), "42" ; "i64")]
+    #[test_case(Value::String("hello".into()), "hello" ; "string")]
+    #[test_case(Value::Array(vec![true, false].into()), "[true,false]" ; "bool array")]
+    #[test_case(Value::Array(vec![1, 2].into()), "[1,2]" ; "i64 array")]
+    #[test_case(Value::Array(vec![1.0, 2.5].into()), "[1.0,2.5]" ; "f64 array")]
+    #[test_case(
+        Value::Array(vec![StringValue::from("a"), StringValue::from("b \"c\"")].into()),
+        "[\"a\",\"b \\\"c\\\"\"]" ;
+        "string array"
+    )]
+    fn value_to_limited_len_string(value: Value, expected: &'static str) {
+        let actual: LimitedLenString<8192> = (&value).into();
+        assert_eq!(expected, actual.as_ref());
+    }
+
+    #[test_case("abc", "abc", false ; "short")]
+    #[test_case("abcdef", "abcd", true ; "ascii")]
+    #[test_case("ab\u{e4}\u{e4}", "ab\u{e4}", true ; "two byte char")]
+    #[test_case("a\u{1f600}", "a", true ; "four byte char")]
+    fn truncate_at_char_boundary(input: &'static str, expected: &'static str, truncated: bool) {
+        let from_str: LimitedLenString<4> = input.into();
+        assert_eq!(expected, from_str.as_ref());
+        assert_eq!(truncated, from_str.truncated);
+
+        let from_string: LimitedLenString<4> = input.to_string().into();
+        assert_eq!(expected, from_string.as_ref());
+        assert_eq!(truncated, from_string.truncated);
+    }
+
+    #[test_case(None, "\u{65e5}\u{672c}\u{8a9e}" ; "no marker")]
+    #[test_case(Some("..."), "\u{65e5}\u{672c}..." ; "marker")]
+    #[test_case(Some("0123456789ab"), "\u{65e5}\u{672c}\u{8a9e}" ; "marker longer than limit")]
+    fn marker(marker: Option<&str>, expected: &'static str) {
+        let mut value: LimitedLenString<10> = "\u{65e5}\u{672c}\u{8a9e}\u{306e}".into();
+        assert!(value.finish_truncation(marker));
+        assert_eq!(expected, value.as_ref());
+    }
+
+    #[test]
+    fn count_truncated_fields() {
+        let mut tags = Tags::new();
+        tags.insert(DEVICE_LOCALE, "\u{1f600}".repeat(20));
+        let mut properties = Properties::new();
+        properties.insert("k".repeat(200).into(), "v".into());
+        properties.insert("short".into(), "v".repeat(9000).into());
+        let mut envelopes = vec![Envelope {
+            name: "Test",
+            time: "2020-06-21:10:40:00Z".into(),
+            sample_rate: None,
+            i_key: None,
+            tags: Some(tags),
+            data: Some(Data::Message(MessageData {
+                ver: 2,
+                severity_level: None,
+                message: "m".repeat(33000).into(),
+                properties: Some(properties),
+            })),
+        }];
+        let diagnostics = Diagnostics::new();
+        finish_truncation(&mut envelopes, Some("..."), &diagnostics);
+
+        let truncated_fields = diagnostics.truncated_fields();
+        assert_eq!(Some(&1), truncated_fields.get("ai.device.locale"));
+        assert_eq!(Some(&1), truncated_fields.get("MessageData.message"));
+        assert_eq!(Some(&1), truncated_fields.get("MessageData.properties"));
+        assert_eq!(3, truncated_fields.len());
+
+        let envelope = envelopes.pop().unwrap();
+        let locale = envelope.tags.unwrap().get(&DEVICE_LOCALE).unwrap().clone();
+        assert!(locale.len() <= 64);
+        assert!(locale.ends_with("..."));
+        match envelope.data.unwrap() {
+            Data::Message(data) => {
+                assert_eq!(32768, data.message.as_ref().len());
+                assert!(data.message.as_ref().ends_with("..."));
+                let properties = data.properties.unwrap();
+                assert!(properties.keys().all(|key| key.as_ref().len() <= 150));
+                assert!(properties.keys().any(|key| key.as_ref().ends_with("...")));
+            }
+            _ => panic!("we should not get here"),
+        }
+    }
+}
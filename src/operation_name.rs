@@ -0,0 +1,180 @@
+use crate::{models::context_tag_keys::attrs, tags::get_operation_name};
+use opentelemetry::{
+    trace::{Span as _, SpanId, TraceContextExt as _, TraceResult},
+    Context,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    trace::{Span, SpanProcessor},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Number of operation names of ended spans which are kept until their spans are exported. Same
+/// as the default queue size of the batch span processor. If spans are dropped before they are
+/// exported, the oldest names are dropped after twice as many spans ended.
+const MAX_ENDED_OPERATION_NAMES: usize = 2048;
+
+/// Operation names propagated from request spans to the spans started within them, shared between
+/// the `OperationNameProcessor` and the exporter, which sends them as operation name context tag.
+///
+/// The names are not recorded as span attributes, so they don't show up in other exporters.
+#[derive(Debug, Default)]
+pub(crate) struct OperationNames {
+    state: Mutex<OperationNamesState>,
+}
+
+#[derive(Debug, Default)]
+struct OperationNamesState {
+    /// Operation names of started spans and whether they were propagated from the parent span.
+    started: HashMap<SpanId, (Arc<str>, bool)>,
+    /// Propagated operation names of ended spans, until the spans are exported.
+    ended: HashMap<SpanId, Arc<str>>,
+    /// Previous generation of `ended`, replaced whenever `ended` is full.
+    ended_previous: HashMap<SpanId, Arc<str>>,
+}
+
+impl OperationNames {
+    /// Removes and returns the propagated operation name of an ended span.
+    pub(crate) fn take(&self, span_id: SpanId) -> Option<Arc<str>> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .ended
+            .remove(&span_id)
+            .or_else(|| state.ended_previous.remove(&span_id))
+    }
+
+    /// Propagates the operation name of the parent span, if it has one. Returns whether it did.
+    fn start(&self, span_id: SpanId, parent_span_id: Option<SpanId>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let operation_name = match parent_span_id.and_then(|id| state.started.get(&id)) {
+            Some((operation_name, _)) => operation_name.clone(),
+            None => return false,
+        };
+        state.started.insert(span_id, (operation_name, true));
+        true
+    }
+
+    fn start_request(&self, span_id: SpanId, operation_name: Arc<str>) {
+        self.state
+            .lock()
+            .unwrap()
+            .started
+            .insert(span_id, (operation_name, false));
+    }
+
+    fn end(&self, span_id: SpanId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((operation_name, true)) = state.started.remove(&span_id) {
+            if state.ended.len() >= MAX_ENDED_OPERATION_NAMES {
+                state.ended_previous = std::mem::take(&mut state.ended);
+            }
+            state.ended.insert(span_id, operation_name);
+        }
+    }
+}
+
+/// Propagates the operation name of request spans to all spans started within them.
+///
+/// The operation name of a request is determined from the attributes it was started with, so
+/// `http.route` has to be set when starting the request span.
+#[derive(Debug)]
+pub(crate) struct OperationNameProcessor {
+    operation_names: Arc<OperationNames>,
+}
+
+impl OperationNameProcessor {
+    pub(crate) fn new(operation_names: Arc<OperationNames>) -> Self {
+        OperationNameProcessor { operation_names }
+    }
+}
+
+impl SpanProcessor for OperationNameProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        let span_id = span.span_context().span_id();
+        let parent = cx.span();
+        let parent = parent.span_context();
+        let parent_span_id =
+            Some(parent.span_id()).filter(|_| parent.is_valid() && !parent.is_remote());
+        if self.operation_names.start(span_id, parent_span_id) {
+            return;
+        }
+
+        // Reading a span requires copying it, which is only done for spans that aren't within a
+        // request in this process.
+        let data = match span.exported_data() {
+            Some(data) => data,
+            None => return,
+        };
+        // Same precedence as the operation name context tag of the span itself.
+        if let Some(operation_name) = get_operation_name(&data).or_else(|| {
+            data.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == attrs::OPERATION_NAME)
+                .map(|kv| kv.value.as_str().into_owned())
+        }) {
+            self.operation_names
+                .start_request(span_id, operation_name.into());
+        }
+    }
+
+    fn on_end(&self, span: SpanData) {
+        self.operation_names.end(span.span_context.span_id());
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span_id(id: u64) -> SpanId {
+        SpanId::from_bytes(id.to_be_bytes())
+    }
+
+    #[test]
+    fn keep_propagated_names_of_ended_spans_until_taken() {
+        let operation_names = OperationNames::default();
+        let request = span_id(1);
+        operation_names.start_request(request, "GET /users".into());
+        operation_names.start(span_id(2), Some(request));
+        operation_names.start(span_id(3), Some(span_id(2)));
+        operation_names.end(span_id(3));
+        operation_names.end(span_id(2));
+        operation_names.end(request);
+
+        // Only names propagated to child spans are kept.
+        assert_eq!(None, operation_names.take(request));
+        assert_eq!(Some("GET /users".into()), operation_names.take(span_id(3)));
+        assert_eq!(Some("GET /users".into()), operation_names.take(span_id(2)));
+        assert_eq!(None, operation_names.take(span_id(2)));
+        assert!(operation_names.state.lock().unwrap().started.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_names_of_spans_which_are_never_exported() {
+        let operation_names = OperationNames::default();
+        let request = span_id(u64::MAX);
+        operation_names.start_request(request, "GET /users".into());
+        for i in 1..=(2 * MAX_ENDED_OPERATION_NAMES + 1) as u64 {
+            operation_names.start(span_id(i), Some(request));
+            operation_names.end(span_id(i));
+        }
+
+        assert_eq!(None, operation_names.take(span_id(1)));
+        assert!(operation_names
+            .take(span_id(MAX_ENDED_OPERATION_NAMES as u64 + 1))
+            .is_some());
+        let state = operation_names.state.lock().unwrap();
+        assert!(state.ended.len() + state.ended_previous.len() <= 2 * MAX_ENDED_OPERATION_NAMES);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    convert::time_to_string,
+    models::{Data, DataPoint, DataPointType, Envelope, MetricData},
+    periodic::{PeriodicTask, Schedule},
+    process::private_bytes,
+    tags::get_tags_from_attrs,
+    Error,
+};
+use opentelemetry::{trace::TraceResult, Context};
+use opentelemetry_http::HttpClient;
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    runtime::RuntimeChannel,
+    trace::{Span, SpanProcessor},
+    Resource,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use sysinfo::{Pid, ProcessRefreshKind, System};
+
+const METRIC_PROCESS_TIME: &str = "\\Process(??APP_WIN32_PROC??)\\% Processor Time";
+const METRIC_PROCESS_TIME_NORMALIZED: &str =
+    "\\Process(??APP_WIN32_PROC??)\\% Processor Time Normalized";
+const METRIC_PRIVATE_BYTES: &str = "\\Process(??APP_WIN32_PROC??)\\Private Bytes";
+const METRIC_AVAILABLE_BYTES: &str = "\\Memory\\Available Bytes";
+const METRIC_THREAD_COUNT: &str = "\\Process(??APP_WIN32_PROC??)\\Thread Count";
+const METRIC_HANDLE_COUNT: &str = "\\Process(??APP_WIN32_PROC??)\\Handle Count";
+const METRIC_IO_RATE: &str = "\\Process(??APP_WIN32_PROC??)\\IO Data Bytes/sec";
+
+/// Periodically sends performance counters of the current process, which end up in the
+/// `performanceCounters` table of Application Insights.
+///
+/// This is a span processor, so it runs as long as the tracer provider. It ignores all spans.
+pub(crate) struct PerformanceCountersCollector<R: RuntimeChannel> {
+    task: PeriodicTask<R>,
+}
+
+impl<R: RuntimeChannel> std::fmt::Debug for PerformanceCountersCollector<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerformanceCountersCollector").finish()
+    }
+}
+
+impl<R: RuntimeChannel> PerformanceCountersCollector<R> {
+    pub(crate) fn new<C: HttpClient + 'static>(
+        client: Arc<C>,
+        endpoint: Arc<http::Uri>,
+        instrumentation_key: String,
+        resource: Resource,
+        interval: Duration,
+        runtime: R,
+    ) -> PerformanceCountersCollector<R> {
+        let tags = get_tags_from_attrs(resource.iter());
+        let mut counters = PerformanceCounters::new();
+        let schedule = Schedule {
+            interval,
+            send_immediately: false,
+            send_on_shutdown: false,
+        };
+        let task = PeriodicTask::spawn(
+            client,
+            endpoint,
+            schedule,
+            Error::PerformanceCountersShutdown,
+            move || {
+                let time = time_to_string(SystemTime::now());
+                counters
+                    .collect()
+                    .into_iter()
+                    .map(|(name, value)| Envelope {
+                        name: "Microsoft.ApplicationInsights.Metric",
+                        time: time.clone().into(),
+                        sample_rate: None,
+                        i_key: Some(instrumentation_key.clone().into()),
+                        tags: Some(tags.clone()),
+                        data: Some(Data::Metric(MetricData {
+                            ver: 2,
+                            metrics: vec![DataPoint {
+                                ns: None,
+                                name: name.into(),
+                                kind: Some(DataPointType::Measurement),
+                                value,
+                            }],
+                            properties: None,
+                        })),
+                    })
+                    .collect()
+            },
+            runtime,
+        );
+        PerformanceCountersCollector { task }
+    }
+}
+
+impl<R: RuntimeChannel> SpanProcessor for PerformanceCountersCollector<R> {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, _span: SpanData) {}
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.task.shutdown()
+    }
+}
+
+struct PerformanceCounters {
+    system: System,
+    pid: Option<Pid>,
+    last_collection_time: Instant,
+}
+
+impl PerformanceCounters {
+    fn new() -> Self {
+        let mut counters = Self {
+            system: System::new(),
+            pid: sysinfo::get_current_pid().ok(),
+            last_collection_time: Instant::now(),
+        };
+        // CPU usage and IO rates are computed from the difference to the previous refresh.
+        counters.refresh();
+        counters
+    }
+
+    fn refresh(&mut self) {
+        self.system.refresh_cpu();
+        self.system.refresh_memory();
+        if let Some(pid) = self.pid {
+            self.system.refresh_process_specifics(
+                pid,
+                ProcessRefreshKind::new()
+                    .with_cpu()
+                    .with_memory()
+                    .with_disk_usage(),
+            );
+        }
+    }
+
+    fn collect(&mut self) -> Vec<(&'static str, f64)> {
+        self.refresh();
+        let now = Instant::now();
+        let elapsed_seconds = now.duration_since(self.last_collection_time).as_secs_f64();
+        self.last_collection_time = now;
+
+        let mut metrics = vec![(
+            METRIC_AVAILABLE_BYTES,
+            self.system.available_memory() as f64,
+        )];
+        if let Some(process) = self.pid.and_then(|pid| self.system.process(pid)) {
+            let cpu_usage = f64::from(process.cpu_usage());
+            let cpu_count = self.system.cpus().len().max(1) as f64;
+            metrics.push((METRIC_PROCESS_TIME, cpu_usage));
+            metrics.push((METRIC_PROCESS_TIME_NORMALIZED, cpu_usage / cpu_count));
+            if let Some(private_bytes) = private_bytes() {
+                metrics.push((METRIC_PRIVATE_BYTES, private_bytes as f64));
+            }
+            if let Some(tasks) = process.tasks() {
+                metrics.push((METRIC_THREAD_COUNT, tasks.len() as f64));
+            }
+            if elapsed_seconds > 0. {
+                let disk_usage = process.disk_usage();
+                let io_bytes = disk_usage.read_bytes + disk_usage.written_bytes;
+                metrics.push((METRIC_IO_RATE, io_bytes as f64 / elapsed_seconds));
+            }
+        }
+        if let Some(handle_count) = open_file_descriptor_count() {
+            metrics.push((METRIC_HANDLE_COUNT, handle_count as f64));
+        }
+        metrics
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_file_descriptor_count() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_file_descriptor_count() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_process_counters() {
+        let mut counters = PerformanceCounters::new();
+        let metrics = counters.collect();
+        let names: Vec<_> = metrics.iter().map(|(name, _)| *name).collect();
+        assert!(names.contains(&METRIC_AVAILABLE_BYTES));
+        assert!(names.contains(&METRIC_PROCESS_TIME));
+        assert!(names.contains(&METRIC_IO_RATE));
+        if cfg!(target_os = "linux") {
+            let private_bytes = metrics
+                .iter()
+                .find(|(name, _)| *name == METRIC_PRIVATE_BYTES)
+                .map(|(_, value)| *value);
+            assert!(private_bytes.unwrap_or_default() > 0.);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{models::Envelope, Error};
+use futures_util::{pin_mut, select_biased, FutureExt as _, StreamExt as _};
+use opentelemetry::trace::{TraceError, TraceResult};
+use opentelemetry_http::HttpClient;
+use opentelemetry_sdk::runtime::{RuntimeChannel, TrySend, TrySendError};
+use std::{sync::Arc, time::Duration};
+
+/// When a [`PeriodicTask`] collects and sends telemetry.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Schedule {
+    pub(crate) interval: Duration,
+    /// Send once right away instead of waiting for the first interval.
+    pub(crate) send_immediately: bool,
+    /// Send once more when the task is stopped.
+    pub(crate) send_on_shutdown: bool,
+}
+
+/// Background task on the async runtime, which periodically collects telemetry and sends it to
+/// Application Insights until it's shut down or dropped.
+///
+/// It's owned by span processors, which run as long as the tracer provider.
+pub(crate) struct PeriodicTask<R: RuntimeChannel> {
+    message_sender: R::Sender<Message>,
+    shutdown_error: fn(TrySendError) -> Error,
+    is_shutdown: bool,
+}
+
+impl<R: RuntimeChannel> std::fmt::Debug for PeriodicTask<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeriodicTask")
+            .field("is_shutdown", &self.is_shutdown)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Message {
+    Send,
+    Stop,
+}
+
+impl<R: RuntimeChannel> PeriodicTask<R> {
+    /// Spawns the task. Errors of stopping it are wrapped with `shutdown_error`. Nothing is sent
+    /// if `collect` returns no envelopes.
+    pub(crate) fn spawn<C: HttpClient + 'static>(
+        client: Arc<C>,
+        endpoint: Arc<http::Uri>,
+        schedule: Schedule,
+        shutdown_error: fn(TrySendError) -> Error,
+        mut collect: impl FnMut() -> Vec<Envelope> + Send + 'static,
+        runtime: R,
+    ) -> PeriodicTask<R> {
+        let (message_sender, message_receiver) = runtime.batch_message_channel(1);
+        let delay_runtime = runtime.clone();
+        runtime.spawn(Box::pin(async move {
+            let message_receiver = message_receiver.fuse();
+            pin_mut!(message_receiver);
+
+            let mut send = schedule.send_immediately;
+            loop {
+                if send {
+                    send_envelopes(client.as_ref(), endpoint.as_ref(), collect()).await;
+                }
+
+                let mut delay = delay_runtime.delay(schedule.interval).fuse();
+                let msg = select_biased! {
+                    msg = message_receiver.next() => msg.unwrap_or(Message::Stop),
+                    _ = delay => Message::Send,
+                };
+                match msg {
+                    Message::Send => send = true,
+                    Message::Stop => {
+                        if schedule.send_on_shutdown {
+                            send_envelopes(client.as_ref(), endpoint.as_ref(), collect()).await;
+                        }
+                        break;
+                    }
+                }
+            }
+        }));
+
+        PeriodicTask {
+            message_sender,
+            shutdown_error,
+            is_shutdown: false,
+        }
+    }
+
+    /// Triggers sending right away and restarts the interval. Doesn't wait until it's sent.
+    pub(crate) fn send_now(&self) -> Result<(), TrySendError> {
+        self.message_sender.try_send(Message::Send)
+    }
+
+    /// Stops the task. Doesn't wait until it's stopped.
+    pub(crate) fn shutdown(&mut self) -> TraceResult<()> {
+        if self.is_shutdown {
+            return Err((self.shutdown_error)(TrySendError::ChannelClosed).into());
+        }
+        self.is_shutdown = true;
+        self.message_sender
+            .try_send(Message::Stop)
+            .map_err(self.shutdown_error)?;
+        Ok(())
+    }
+}
+
+impl<R: RuntimeChannel> Drop for PeriodicTask<R> {
+    fn drop(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+        if let Err(err) = self.shutdown() {
+            opentelemetry::global::handle_error(err);
+        }
+    }
+}
+
+async fn send_envelopes(client: &dyn HttpClient, endpoint: &http::Uri, envelopes: Vec<Envelope>) {
+    if envelopes.is_empty() {
+        return;
+    }
+    if let Err(err) = crate::uploader::send(client, endpoint, envelopes).await {
+        opentelemetry::global::handle_error(TraceError::from(err));
+    }
+}
@@ -0,0 +1,36 @@
+/// Private memory of the current process like the Windows private bytes counter: anonymous
+/// memory, which is resident or swapped out. Unlike the resident set size, this doesn't include
+/// shared libraries and mapped files.
+#[cfg(target_os = "linux")]
+pub(crate) fn private_bytes() -> Option<u64> {
+    parse_proc_status_private_bytes(&std::fs::read_to_string("/proc/self/status").ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn private_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(any(target_os = "linux", test))]
+fn parse_proc_status_private_bytes(status: &str) -> Option<u64> {
+    let kilobytes = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|value| value.trim().strip_suffix("kB"))
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    Some((kilobytes("RssAnon:")? + kilobytes("VmSwap:").unwrap_or(0)) * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_bytes_from_proc_status() {
+        let status = "Name:\tapp\nVmRSS:\t   20480 kB\nRssAnon:\t    8192 kB\nRssFile:\t   12288 kB\nVmSwap:\t    1024 kB\n";
+        assert_eq!(Some(9216 * 1024), parse_proc_status_private_bytes(status));
+        assert_eq!(None, parse_proc_status_private_bytes("Name:\tapp\n"));
+    }
+}
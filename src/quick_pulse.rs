@@ -1,38 +1,60 @@
 use crate::{
-    models::{context_tag_keys, QuickPulseEnvelope, QuickPulseMetric},
-    tags::get_tags_from_attrs,
-    trace::{get_duration, is_remote_dependency_success, is_request_success, EVENT_NAME_EXCEPTION},
+    models::{
+        context_tag_keys, CollectionConfigurationInfo, ExceptionData, MessageData, Properties,
+        QuickPulseDocument, QuickPulseDocumentData, QuickPulseDocumentProperty, QuickPulseEnvelope,
+        QuickPulseMetric, RemoteDependencyData, RequestData, SeverityLevel,
+    },
+    process::private_bytes,
+    quick_pulse_configuration::{CollectionConfiguration, TelemetryType},
+    tags::{get_tags_for_span, get_tags_from_attrs},
+    trace::{
+        get_duration, is_remote_dependency_success, is_request_success, EVENT_NAME_CUSTOM,
+        EVENT_NAME_EXCEPTION,
+    },
     uploader_quick_pulse::{self, PostOrPing},
     Error,
 };
 use futures_util::{pin_mut, select_biased, FutureExt as _, StreamExt as _};
+#[cfg(feature = "live-metrics-logs")]
+use opentelemetry::logs::{AnyValue, LogRecord, LogResult, Severity};
 use opentelemetry::{
-    trace::{SpanKind, TraceResult},
+    trace::{Event, SpanKind, TraceError, TraceResult},
     Context, Key,
 };
 use opentelemetry_http::HttpClient;
+#[cfg(feature = "live-metrics-logs")]
+use opentelemetry_sdk::{export::logs::LogData, logs::LogProcessor};
 use opentelemetry_sdk::{
     export::trace::SpanData,
-    runtime::{RuntimeChannel, TrySend},
+    runtime::{RuntimeChannel, TrySend, TrySendError},
     trace::{IdGenerator as _, RandomIdGenerator, Span, SpanProcessor},
     Resource,
 };
 use opentelemetry_semantic_conventions as semcov;
+#[cfg(feature = "metrics")]
+use std::collections::BTreeMap;
 use std::{
+    any::Any,
+    borrow::Cow,
+    panic::AssertUnwindSafe,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        mpsc, Arc, Mutex,
     },
     time::Duration,
     time::SystemTime,
 };
-use sysinfo::{CpuRefreshKind, MemoryRefreshKind, RefreshKind, System};
+use sysinfo::{CpuRefreshKind, MemoryRefreshKind, Pid, ProcessRefreshKind, RefreshKind, System};
 
-const MAX_POST_WAIT_TIME: Duration = Duration::from_secs(20);
-const MAX_PING_WAIT_TIME: Duration = Duration::from_secs(60);
-const FALLBACK_INTERVAL: Duration = Duration::from_secs(60);
-const PING_INTERVAL: Duration = Duration::from_secs(5);
-const POST_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_POST_WAIT_TIME: Duration = Duration::from_secs(20);
+const DEFAULT_MAX_PING_WAIT_TIME: Duration = Duration::from_secs(60);
+const DEFAULT_FALLBACK_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_POST_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of sample telemetry documents sent with a single post, so a burst of failures
+/// doesn't result in huge requests.
+const MAX_DOCUMENTS_PER_POST: usize = 30;
 
 const METRIC_PROCESSOR_TIME: &str = "\\Processor(_Total)\\% Processor Time";
 const METRIC_COMMITTED_BYTES: &str = "\\Memory\\Committed Bytes";
@@ -43,11 +65,24 @@ const METRIC_DEPENDENCY_RATE: &str = "\\ApplicationInsights\\Dependency Calls/Se
 const METRIC_DEPENDENCY_FAILURE_RATE: &str = "\\ApplicationInsights\\Dependency Calls Failed/Sec";
 const METRIC_DEPENDENCY_DURATION: &str = "\\ApplicationInsights\\Dependency Call Duration";
 const METRIC_EXCEPTION_RATE: &str = "\\ApplicationInsights\\Exceptions/Sec";
+/// Counters sent with every post, which application defined counters can't replace.
+const BUILT_IN_METRICS: &[&str] = &[
+    METRIC_PROCESSOR_TIME,
+    METRIC_COMMITTED_BYTES,
+    METRIC_REQUEST_RATE,
+    METRIC_REQUEST_FAILURE_RATE,
+    METRIC_REQUEST_DURATION,
+    METRIC_DEPENDENCY_RATE,
+    METRIC_DEPENDENCY_FAILURE_RATE,
+    METRIC_DEPENDENCY_DURATION,
+    METRIC_EXCEPTION_RATE,
+];
 
 pub(crate) struct QuickPulseManager<R: RuntimeChannel> {
-    is_collecting: Arc<AtomicBool>,
-    metrics_collector: Arc<Mutex<MetricsCollector>>,
+    collector: QuickPulseCollector,
     message_sender: R::Sender<Message>,
+    shutdown_timeout: Duration,
+    is_shutdown: bool,
 }
 
 impl<R: RuntimeChannel> std::fmt::Debug for QuickPulseManager<R> {
@@ -59,7 +94,8 @@ impl<R: RuntimeChannel> std::fmt::Debug for QuickPulseManager<R> {
 #[derive(Debug)]
 enum Message {
     Send,
-    Stop,
+    /// Stop the background task, which reports back on the given channel once it's done.
+    Stop(mpsc::SyncSender<()>),
 }
 
 impl<R: RuntimeChannel> QuickPulseManager<R> {
@@ -68,55 +104,48 @@ impl<R: RuntimeChannel> QuickPulseManager<R> {
         live_metrics_endpoint: http::Uri,
         instrumentation_key: String,
         resource: Resource,
+        collector: QuickPulseCollector,
+        config: LiveMetricsConfig,
         runtime: R,
     ) -> QuickPulseManager<R> {
         let (message_sender, message_receiver) = runtime.batch_message_channel(1);
         let delay_runtime = runtime.clone();
-        let is_collecting_outer = Arc::new(AtomicBool::new(false));
-        let is_collecting = is_collecting_outer.clone();
-        let metrics_collector_outer = Arc::new(Mutex::new(MetricsCollector::new()));
-        let metrics_collector = metrics_collector_outer.clone();
+        let shutdown_timeout = config.shutdown_timeout;
+        let mut worker = QuickPulseWorker::new(
+            client,
+            live_metrics_endpoint,
+            instrumentation_key,
+            resource,
+            collector.clone(),
+            config,
+        );
         runtime.spawn(Box::pin(async move {
-            let mut sender =
-                QuickPulseSender::new(client, live_metrics_endpoint, instrumentation_key, resource);
-
             let message_receiver = message_receiver.fuse();
             pin_mut!(message_receiver);
-            let mut send_delay = delay_runtime.delay(PING_INTERVAL).fuse();
+            let mut send_delay = delay_runtime.delay(worker.start_delay()).fuse();
 
-            loop {
+            let done = loop {
                 let msg = select_biased! {
-                    msg = message_receiver.next() => msg.unwrap_or(Message::Stop),
-                    _ = send_delay => Message::Send
+                    msg = message_receiver.next() => msg,
+                    _ = send_delay => Some(Message::Send)
                 };
                 match msg {
-                    Message::Send => {
-                        let curr_is_collecting = is_collecting.load(Ordering::SeqCst);
-                        let metrics = if curr_is_collecting {
-                            metrics_collector.lock().unwrap().collect_and_reset()
-                        } else {
-                            Vec::new()
-                        };
-                        let (next_is_collecting, next_timeout) =
-                            sender.send(curr_is_collecting, metrics).await;
-                        if curr_is_collecting != next_is_collecting {
-                            is_collecting.store(next_is_collecting, Ordering::SeqCst);
-                            if next_is_collecting {
-                                // Reset last collection time to get accurate metrics on next collection.
-                                metrics_collector.lock().unwrap().reset();
-                            }
-                        }
+                    Some(Message::Send) => {
+                        let next_timeout = worker.send().await;
                         send_delay = delay_runtime.delay(next_timeout).fuse();
                     }
-                    Message::Stop => break,
+                    Some(Message::Stop(done)) => break Some(done),
+                    None => break None,
                 }
-            }
+            };
+            worker.finish(done).await;
         }));
 
         QuickPulseManager {
-            is_collecting: is_collecting_outer,
-            metrics_collector: metrics_collector_outer,
+            collector,
             message_sender,
+            shutdown_timeout,
+            is_shutdown: false,
         }
     }
 }
@@ -125,9 +154,7 @@ impl<R: RuntimeChannel> SpanProcessor for QuickPulseManager<R> {
     fn on_start(&self, _span: &mut Span, _cx: &Context) {}
 
     fn on_end(&self, span: SpanData) {
-        if self.is_collecting.load(Ordering::SeqCst) {
-            self.metrics_collector.lock().unwrap().count_span(span);
-        }
+        self.collector.count_span(span);
     }
 
     fn force_flush(&self) -> TraceResult<()> {
@@ -135,21 +162,657 @@ impl<R: RuntimeChannel> SpanProcessor for QuickPulseManager<R> {
     }
 
     fn shutdown(&mut self) -> TraceResult<()> {
+        if self.is_shutdown {
+            return Err(Error::QuickPulseStopped.into());
+        }
+        self.is_shutdown = true;
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
         self.message_sender
-            .try_send(Message::Stop)
-            .map_err(Error::QuickPulseShutdown)?;
-        Ok(())
+            .try_send(Message::Stop(done_sender))
+            .map_err(|err| match err {
+                TrySendError::ChannelClosed => Error::QuickPulseStopped,
+                err => Error::QuickPulseShutdown(err),
+            })?;
+        wait_for_stop(done_receiver, self.shutdown_timeout)
     }
 }
 
 impl<R: RuntimeChannel> Drop for QuickPulseManager<R> {
     fn drop(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+        if let Err(err) = self.shutdown() {
+            opentelemetry::global::handle_error(err);
+        }
+    }
+}
+
+/// Live metrics manager, which runs on a dedicated thread instead of an async runtime. Used with
+/// the simple span processor, where no runtime is available. Requests are driven by blocking on
+/// the HTTP client, so this needs a client which works without an async runtime. Clients which
+/// need one, like the async `reqwest::Client`, panic. The thread reports the panic and stops.
+pub(crate) struct QuickPulseThreadManager {
+    collector: QuickPulseCollector,
+    message_sender: mpsc::SyncSender<Message>,
+    shutdown_timeout: Duration,
+    is_shutdown: bool,
+}
+
+impl std::fmt::Debug for QuickPulseThreadManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuickPulseThreadManager").finish()
+    }
+}
+
+impl QuickPulseThreadManager {
+    pub(crate) fn new<C: HttpClient + 'static>(
+        client: Arc<C>,
+        live_metrics_endpoint: http::Uri,
+        instrumentation_key: String,
+        resource: Resource,
+        collector: QuickPulseCollector,
+        config: LiveMetricsConfig,
+    ) -> QuickPulseThreadManager {
+        let (message_sender, message_receiver) = mpsc::sync_channel(1);
+        let shutdown_timeout = config.shutdown_timeout;
+        let mut worker = QuickPulseWorker::new(
+            client,
+            live_metrics_endpoint,
+            instrumentation_key,
+            resource,
+            collector.clone(),
+            config,
+        );
+        let spawn_result = std::thread::Builder::new()
+            .name("opentelemetry-application-insights-live-metrics".into())
+            .spawn(move || {
+                let collector = worker.collector.clone();
+                let result = std::panic::catch_unwind(AssertUnwindSafe(move || {
+                    let mut send_delay = worker.start_delay();
+                    let done = loop {
+                        match message_receiver.recv_timeout(send_delay) {
+                            Err(mpsc::RecvTimeoutError::Timeout) | Ok(Message::Send) => {
+                                send_delay = futures_executor::block_on(worker.send());
+                            }
+                            Ok(Message::Stop(done)) => break Some(done),
+                            Err(mpsc::RecvTimeoutError::Disconnected) => break None,
+                        }
+                    };
+                    futures_executor::block_on(worker.finish(done));
+                }));
+                if let Err(panic) = result {
+                    collector.is_collecting.store(false, Ordering::SeqCst);
+                    collector.status.set(LiveMetricsState::Idle);
+                    opentelemetry::global::handle_error(TraceError::from(
+                        Error::QuickPulseThreadPanic(panic_message(panic.as_ref())),
+                    ));
+                }
+            });
+        if let Err(err) = spawn_result {
+            opentelemetry::global::handle_error(TraceError::from(Error::QuickPulseThread(err)));
+        }
+
+        QuickPulseThreadManager {
+            collector,
+            message_sender,
+            shutdown_timeout,
+            is_shutdown: false,
+        }
+    }
+}
+
+impl SpanProcessor for QuickPulseThreadManager {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.collector.count_span(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        if self.is_shutdown {
+            return Err(Error::QuickPulseStopped.into());
+        }
+        self.is_shutdown = true;
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
+        self.message_sender
+            .try_send(Message::Stop(done_sender))
+            .map_err(|err| match err {
+                mpsc::TrySendError::Full(_) => Error::QuickPulseShutdown(TrySendError::ChannelFull),
+                mpsc::TrySendError::Disconnected(_) => Error::QuickPulseStopped,
+            })?;
+        wait_for_stop(done_receiver, self.shutdown_timeout)
+    }
+}
+
+impl Drop for QuickPulseThreadManager {
+    fn drop(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
         if let Err(err) = self.shutdown() {
             opentelemetry::global::handle_error(err);
         }
     }
 }
 
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".into())
+}
+
+/// Waits until the background task confirms it stopped. The task drops the channel without
+/// confirming if it's gone already, e.g. because the runtime shut down.
+fn wait_for_stop(done_receiver: mpsc::Receiver<()>, timeout: Duration) -> TraceResult<()> {
+    if timeout.is_zero() {
+        return Ok(());
+    }
+    match done_receiver.recv_timeout(timeout) {
+        Ok(()) => Ok(()),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::QuickPulseShutdownTimeout.into()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::QuickPulseStopped.into()),
+    }
+}
+
+/// State of the background task that collects and sends live metrics. Shared by the runtime and
+/// the thread based managers.
+struct QuickPulseWorker<C: HttpClient + 'static> {
+    sender: QuickPulseSender<C>,
+    resource_counters: ResourceCounters,
+    collector: QuickPulseCollector,
+}
+
+impl<C: HttpClient + 'static> QuickPulseWorker<C> {
+    fn new(
+        client: Arc<C>,
+        live_metrics_endpoint: http::Uri,
+        instrumentation_key: String,
+        resource: Resource,
+        collector: QuickPulseCollector,
+        config: LiveMetricsConfig,
+    ) -> Self {
+        collector.status.set(LiveMetricsState::Pinging);
+        let resource_counters = ResourceCounters::new(config.host_counters);
+        QuickPulseWorker {
+            sender: QuickPulseSender::new(
+                client,
+                live_metrics_endpoint,
+                instrumentation_key,
+                resource,
+                config,
+            ),
+            resource_counters,
+            collector,
+        }
+    }
+
+    /// Time to wait before the first ping, which is the ping interval plus a random part of the
+    /// start jitter.
+    fn start_delay(&self) -> Duration {
+        let config = &self.sender.config;
+        let random = u64::from_be_bytes(RandomIdGenerator::default().new_span_id().to_bytes());
+        config.ping_interval + config.start_jitter.mul_f64(random as f64 / u64::MAX as f64)
+    }
+
+    /// Sends a final post if configured and someone is watching, then marks live metrics as idle
+    /// and confirms the stop on `done`, if given.
+    async fn finish(&mut self, done: Option<mpsc::SyncSender<()>>) {
+        if self.sender.config.final_post && self.collector.is_collecting.load(Ordering::SeqCst) {
+            self.send().await;
+        }
+        self.collector.is_collecting.store(false, Ordering::SeqCst);
+        self.collector.status.set(LiveMetricsState::Idle);
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+
+    /// Sends one ping or post and returns the time to wait before the next one.
+    async fn send(&mut self) -> Duration {
+        let is_collecting = &self.collector.is_collecting;
+        let metrics_collector = &self.collector.metrics_collector;
+        let curr_is_collecting = is_collecting.load(Ordering::SeqCst);
+        let (metrics, documents) = if curr_is_collecting {
+            let mut metrics = self.resource_counters.collect();
+            let documents = {
+                let mut metrics_collector = metrics_collector.lock().unwrap();
+                let documents = metrics_collector.take_documents();
+                metrics.extend(metrics_collector.collect_and_reset());
+                documents
+            };
+            // Sampled outside of the lock, so counters may record telemetry themselves.
+            metrics.extend(self.collector.sample_counters());
+            (metrics, documents)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let (next_state, next_timeout, configuration) = self
+            .sender
+            .send(curr_is_collecting, metrics, documents)
+            .await;
+        self.collector.status.set(next_state);
+        let next_is_collecting = next_state == LiveMetricsState::Posting;
+        if let Some(configuration) = configuration {
+            metrics_collector
+                .lock()
+                .unwrap()
+                .set_configuration(CollectionConfiguration::new(configuration));
+        }
+        if curr_is_collecting != next_is_collecting {
+            is_collecting.store(next_is_collecting, Ordering::SeqCst);
+            if next_is_collecting {
+                // Reset last collection time to get accurate metrics on next collection.
+                metrics_collector.lock().unwrap().reset();
+            }
+        }
+        next_timeout
+    }
+}
+
+/// Telemetry counted for live metrics. Shared between the manager, which sends it, and the sources
+/// of telemetry: spans, log records and metrics.
+#[derive(Clone)]
+pub(crate) struct QuickPulseCollector {
+    is_collecting: Arc<AtomicBool>,
+    metrics_collector: Arc<Mutex<MetricsCollector>>,
+    counters: Arc<Mutex<Vec<LiveCounter>>>,
+    status: LiveMetricsStatus,
+}
+
+/// Application defined live metrics counter, sampled with every post.
+#[derive(Clone)]
+struct LiveCounter {
+    name: Cow<'static, str>,
+    sample: Arc<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl Default for QuickPulseCollector {
+    fn default() -> Self {
+        Self {
+            is_collecting: Arc::new(AtomicBool::new(false)),
+            metrics_collector: Arc::new(Mutex::new(MetricsCollector::new())),
+            counters: Arc::new(Mutex::new(Vec::new())),
+            status: LiveMetricsStatus::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for QuickPulseCollector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuickPulseCollector")
+            .field("is_collecting", &self.is_collecting)
+            .finish()
+    }
+}
+
+impl QuickPulseCollector {
+    pub(crate) fn status(&self) -> LiveMetricsStatus {
+        self.status.clone()
+    }
+
+    /// Registers a counter, replacing an existing one with the same name.
+    pub(crate) fn add_counter(
+        &self,
+        name: Cow<'static, str>,
+        sample: Arc<dyn Fn() -> f64 + Send + Sync>,
+    ) {
+        if BUILT_IN_METRICS.contains(&name.as_ref()) {
+            opentelemetry::global::handle_error(TraceError::from(Error::QuickPulseCounterName(
+                name.into_owned(),
+            )));
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        counters.retain(|counter| counter.name != name);
+        counters.push(LiveCounter { name, sample });
+    }
+
+    /// Samples all counters. Counters whose callback panics are reported and removed.
+    fn sample_counters(&self) -> Vec<QuickPulseMetric> {
+        let counters = self.counters.lock().unwrap().clone();
+        let mut metrics = Vec::with_capacity(counters.len());
+        for counter in counters {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| (counter.sample)())) {
+                Ok(value) if value.is_finite() => metrics.push(QuickPulseMetric {
+                    name: counter.name,
+                    value,
+                    weight: 1,
+                }),
+                Ok(_) => {}
+                Err(panic) => {
+                    self.counters
+                        .lock()
+                        .unwrap()
+                        .retain(|other| !Arc::ptr_eq(&other.sample, &counter.sample));
+                    opentelemetry::global::handle_error(TraceError::from(
+                        Error::QuickPulseCounterPanic(
+                            counter.name.into_owned(),
+                            panic_message(panic.as_ref()),
+                        ),
+                    ));
+                }
+            }
+        }
+        metrics
+    }
+
+    fn count_span(&self, span: SpanData) {
+        if self.is_collecting.load(Ordering::SeqCst) {
+            self.metrics_collector.lock().unwrap().count_span(span);
+        }
+    }
+
+    #[cfg(feature = "live-metrics-logs")]
+    fn count_log(&self, data: LogData) {
+        if self.is_collecting.load(Ordering::SeqCst) {
+            self.metrics_collector.lock().unwrap().count_log(data);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn record_metric(&self, name: &str, value: LiveMetricValue) {
+        if self.is_collecting.load(Ordering::SeqCst) {
+            self.metrics_collector
+                .lock()
+                .unwrap()
+                .record_metric(name, value);
+        }
+    }
+}
+
+/// Timings and behavior of live metrics.
+///
+/// Live metrics ping the service until someone opens the Live Metrics portal and then post
+/// metrics every second. If requests keep failing, they back off and only ping every minute.
+///
+/// ```no_run
+/// use opentelemetry_application_insights::LiveMetricsConfig;
+/// use std::time::Duration;
+///
+/// let tracer_provider = opentelemetry_application_insights::new_pipeline_from_env()
+///     .expect("env var APPLICATIONINSIGHTS_CONNECTION_STRING is valid connection string")
+///     .with_client(reqwest::blocking::Client::new())
+///     .with_live_metrics(true)
+///     .with_live_metrics_config(
+///         LiveMetricsConfig::new()
+///             .with_ping_interval(Duration::from_secs(10))
+///             .with_start_jitter(Duration::from_secs(5)),
+///     )
+///     .build_simple();
+/// ```
+#[derive(Debug, Clone)]
+pub struct LiveMetricsConfig {
+    ping_interval: Duration,
+    post_interval: Duration,
+    max_post_wait_time: Duration,
+    max_ping_wait_time: Duration,
+    fallback_interval: Duration,
+    start_jitter: Duration,
+    host_info: bool,
+    final_post: bool,
+    shutdown_timeout: Duration,
+    host_counters: bool,
+}
+
+impl Default for LiveMetricsConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: DEFAULT_PING_INTERVAL,
+            post_interval: DEFAULT_POST_INTERVAL,
+            max_post_wait_time: DEFAULT_MAX_POST_WAIT_TIME,
+            max_ping_wait_time: DEFAULT_MAX_PING_WAIT_TIME,
+            fallback_interval: DEFAULT_FALLBACK_INTERVAL,
+            start_jitter: Duration::ZERO,
+            host_info: true,
+            final_post: false,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            host_counters: false,
+        }
+    }
+}
+
+impl LiveMetricsConfig {
+    /// Create a config with the default timings of the other Application Insights SDKs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the interval of pings while nobody watches live metrics. A polling interval requested
+    /// by the service takes precedence. A zero interval is ignored.
+    ///
+    /// Default: 5 seconds
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.ping_interval = interval;
+        }
+        self
+    }
+
+    /// Set the interval of posts while someone watches live metrics. A zero interval is ignored.
+    ///
+    /// Default: 1 second
+    pub fn with_post_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.post_interval = interval;
+        }
+        self
+    }
+
+    /// Set how long posts may fail before falling back to pings.
+    ///
+    /// Default: 20 seconds
+    pub fn with_max_post_wait_time(mut self, wait_time: Duration) -> Self {
+        self.max_post_wait_time = wait_time;
+        self
+    }
+
+    /// Set how long pings may fail before backing off to the fallback interval.
+    ///
+    /// Default: 60 seconds
+    pub fn with_max_ping_wait_time(mut self, wait_time: Duration) -> Self {
+        self.max_ping_wait_time = wait_time;
+        self
+    }
+
+    /// Set the interval of pings after backing off.
+    ///
+    /// Default: 60 seconds
+    pub fn with_fallback_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.fallback_interval = interval;
+        }
+        self
+    }
+
+    /// Delay the first ping by a random duration up to the given jitter, so many instances
+    /// starting at the same time don't ping in lockstep.
+    ///
+    /// Default: 0
+    pub fn with_start_jitter(mut self, jitter: Duration) -> Self {
+        self.start_jitter = jitter;
+        self
+    }
+
+    /// Send machine, instance and role name as headers with pings.
+    ///
+    /// Default: true
+    pub fn with_host_info(mut self, host_info: bool) -> Self {
+        self.host_info = host_info;
+        self
+    }
+
+    /// Report CPU and memory usage of the whole host instead of the current process.
+    ///
+    /// By default, live metrics show the CPU usage of the current process normalized by the number
+    /// of available cores, taking CPU quotas of the cgroup into account, and its private bytes, like
+    /// the other Application Insights SDKs. Private bytes are only available on Linux, the resident
+    /// memory is reported on other platforms.
+    ///
+    /// Host memory usage respects the memory limit of the cgroup on Linux.
+    ///
+    /// Default: false
+    pub fn with_host_counters(mut self, host_counters: bool) -> Self {
+        self.host_counters = host_counters;
+        self
+    }
+
+    /// Send the metrics collected since the last post once more when the tracer provider shuts
+    /// down while someone watches live metrics, so the last second isn't lost.
+    ///
+    /// Default: false
+    pub fn with_final_post(mut self, final_post: bool) -> Self {
+        self.final_post = final_post;
+        self
+    }
+
+    /// Set how long shutting down the tracer provider waits for live metrics to stop, including
+    /// an in-flight request and the final post. A zero timeout doesn't wait.
+    ///
+    /// Shutdown blocks the calling thread while it waits. With `build_batch`, live metrics stop on
+    /// the runtime. If that runtime can't make progress while the calling thread is blocked, e.g.
+    /// because the tracer provider is shut down on the only thread of a current-thread runtime,
+    /// shutdown waits for the full timeout and reports [`Error::QuickPulseShutdownTimeout`]. Shut
+    /// down from another thread, e.g. with `spawn_blocking`, or use a zero timeout there.
+    ///
+    /// [`Error::QuickPulseShutdownTimeout`]: crate::Error::QuickPulseShutdownTimeout
+    ///
+    /// Default: 5 seconds
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+}
+
+/// State of live metrics, see [`LiveMetricsStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LiveMetricsState {
+    /// Live metrics are not running, because the pipeline is not built with live metrics enabled
+    /// or was shut down.
+    Idle,
+    /// Nobody watches live metrics. The service is pinged regularly.
+    Pinging,
+    /// Someone watches live metrics. Metrics and sample telemetry are posted every second.
+    Posting,
+    /// Requests kept failing. The service is only pinged in the fallback interval.
+    BackedOff,
+}
+
+/// Current state of live metrics of a pipeline, e.g. to tell whether live streaming is active.
+///
+/// Get it from [`PipelineBuilder::live_metrics_status`]. It is shared between all clones.
+///
+/// [`PipelineBuilder::live_metrics_status`]: crate::PipelineBuilder::live_metrics_status
+#[derive(Debug, Clone, Default)]
+pub struct LiveMetricsStatus {
+    state: Arc<AtomicU8>,
+}
+
+impl LiveMetricsStatus {
+    /// Current state of live metrics.
+    pub fn state(&self) -> LiveMetricsState {
+        match self.state.load(Ordering::SeqCst) {
+            1 => LiveMetricsState::Pinging,
+            2 => LiveMetricsState::Posting,
+            3 => LiveMetricsState::BackedOff,
+            _ => LiveMetricsState::Idle,
+        }
+    }
+
+    fn set(&self, state: LiveMetricsState) {
+        let value = match state {
+            LiveMetricsState::Idle => 0,
+            LiveMetricsState::Pinging => 1,
+            LiveMetricsState::Posting => 2,
+            LiveMetricsState::BackedOff => 3,
+        };
+        self.state.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Log processor which counts exceptions and sends log records as sample telemetry to live
+/// metrics.
+///
+/// Get it from [`PipelineBuilder::live_metrics_log_processor`] and add it to your
+/// `LoggerProvider`. It only collects data while the pipeline is built with live metrics enabled.
+///
+/// [`PipelineBuilder::live_metrics_log_processor`]: crate::PipelineBuilder::live_metrics_log_processor
+#[cfg(feature = "live-metrics-logs")]
+#[cfg_attr(docsrs, doc(cfg(feature = "live-metrics-logs")))]
+#[derive(Debug)]
+pub struct LiveMetricsLogProcessor {
+    collector: QuickPulseCollector,
+}
+
+#[cfg(feature = "live-metrics-logs")]
+impl LiveMetricsLogProcessor {
+    pub(crate) fn new(collector: QuickPulseCollector) -> Self {
+        Self { collector }
+    }
+}
+
+#[cfg(feature = "live-metrics-logs")]
+impl LogProcessor for LiveMetricsLogProcessor {
+    fn emit(&self, data: LogData) {
+        self.collector.count_log(data);
+    }
+
+    fn force_flush(&self) -> LogResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> LogResult<()> {
+        Ok(())
+    }
+}
+
+/// Measurements of an OpenTelemetry instrument, merged until the next post.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LiveMetricValue {
+    /// Sum of all measurements, used for counters.
+    Sum(f64),
+    /// Latest value, used for gauges and up-down counters.
+    Last(f64),
+    /// Average of all measurements, used for histograms.
+    Avg { sum: f64, count: u64 },
+}
+
+#[cfg(feature = "metrics")]
+impl LiveMetricValue {
+    fn merge(self, other: LiveMetricValue) -> LiveMetricValue {
+        match (self, other) {
+            (LiveMetricValue::Sum(a), LiveMetricValue::Sum(b)) => LiveMetricValue::Sum(a + b),
+            (
+                LiveMetricValue::Avg { sum, count },
+                LiveMetricValue::Avg {
+                    sum: other_sum,
+                    count: other_count,
+                },
+            ) => LiveMetricValue::Avg {
+                sum: sum + other_sum,
+                count: count + other_count,
+            },
+            (_, other) => other,
+        }
+    }
+
+    fn value(self) -> Option<f64> {
+        match self {
+            LiveMetricValue::Sum(value) | LiveMetricValue::Last(value) => Some(value),
+            LiveMetricValue::Avg { count: 0, .. } => None,
+            LiveMetricValue::Avg { sum, count } => Some(sum / count as f64),
+        }
+    }
+}
+
 struct QuickPulseSender<C: HttpClient + 'static> {
     client: Arc<C>,
     host: http::Uri,
@@ -161,6 +824,8 @@ struct QuickPulseSender<C: HttpClient + 'static> {
     machine_name: String,
     instance: String,
     role_name: Option<String>,
+    configuration_etag: String,
+    config: LiveMetricsConfig,
 }
 
 impl<C: HttpClient + 'static> QuickPulseSender<C> {
@@ -169,6 +834,7 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
         host: http::Uri,
         instrumentation_key: String,
         resource: Resource,
+        config: LiveMetricsConfig,
     ) -> Self {
         let mut tags = get_tags_from_attrs(resource.iter());
         let machine_name = resource
@@ -188,6 +854,8 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
                 .remove(context_tag_keys::CLOUD_ROLE_INSTANCE)
                 .unwrap_or_else(|| machine_name.clone()),
             machine_name,
+            configuration_etag: String::new(),
+            config,
         }
     }
 
@@ -195,7 +863,12 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
         &mut self,
         is_collecting: bool,
         metrics: Vec<QuickPulseMetric>,
-    ) -> (bool, Duration) {
+        documents: Vec<QuickPulseDocument>,
+    ) -> (
+        LiveMetricsState,
+        Duration,
+        Option<CollectionConfigurationInfo>,
+    ) {
         let now = SystemTime::now();
         let now_ms = now
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -203,6 +876,7 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
             .unwrap_or(0);
         let envelope = QuickPulseEnvelope {
             metrics,
+            documents,
             invariant_version: 1,
             timestamp: format!("/Date({})/", now_ms),
             version: self.version.clone(),
@@ -216,6 +890,8 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
             self.client.as_ref(),
             &self.host,
             &self.instrumentation_key,
+            &self.configuration_etag,
+            self.config.host_info,
             if is_collecting {
                 PostOrPing::Post
             } else {
@@ -224,8 +900,13 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
             envelope,
         )
         .await;
-        let (last_send_succeeded, mut next_is_collecting) = if let Ok(res) = res {
+        let mut configuration = None;
+        let (last_send_succeeded, next_is_collecting) = if let Ok(res) = res {
             self.last_success_time = now;
+            if let Some(configuration_etag) = res.configuration_etag {
+                self.configuration_etag = configuration_etag;
+            }
+            configuration = res.configuration;
             if let Some(redirected_host) = res.redirected_host {
                 self.host = redirected_host;
             }
@@ -237,32 +918,149 @@ impl<C: HttpClient + 'static> QuickPulseSender<C> {
             (false, is_collecting)
         };
 
-        let mut next_timeout = if next_is_collecting {
-            POST_INTERVAL
-        } else {
-            self.polling_interval_hint.unwrap_or(PING_INTERVAL)
-        };
+        let config = &self.config;
         if !last_send_succeeded {
             let time_since_last_success = now
                 .duration_since(self.last_success_time)
                 .unwrap_or(Duration::MAX);
-            if next_is_collecting && time_since_last_success >= MAX_POST_WAIT_TIME {
-                // Haven't posted successfully in 20 seconds, so wait 60 seconds and ping
-                next_is_collecting = false;
-                next_timeout = FALLBACK_INTERVAL;
-            } else if !next_is_collecting && time_since_last_success >= MAX_PING_WAIT_TIME {
-                // Haven't pinged successfully in 60 seconds, so wait another 60 seconds
-                next_timeout = FALLBACK_INTERVAL;
+            if next_is_collecting && time_since_last_success >= config.max_post_wait_time {
+                // Haven't posted successfully for too long, so fall back to pinging
+                return (
+                    LiveMetricsState::BackedOff,
+                    config.fallback_interval,
+                    configuration,
+                );
+            } else if !next_is_collecting && time_since_last_success >= config.max_ping_wait_time {
+                // Haven't pinged successfully for too long, so ping less often
+                return (
+                    LiveMetricsState::BackedOff,
+                    config.fallback_interval,
+                    configuration,
+                );
             }
         }
 
-        (next_is_collecting, next_timeout)
+        if next_is_collecting {
+            (
+                LiveMetricsState::Posting,
+                config.post_interval,
+                configuration,
+            )
+        } else {
+            (
+                LiveMetricsState::Pinging,
+                self.polling_interval_hint.unwrap_or(config.ping_interval),
+                configuration,
+            )
+        }
     }
 }
 
-struct MetricsCollector {
+/// CPU and memory usage, of the current process by default or of the whole host.
+struct ResourceCounters {
     system: System,
-    system_refresh_kind: RefreshKind,
+    host: bool,
+    pid: Option<Pid>,
+    /// Number of cores available to the process, used to normalize its CPU usage.
+    cpu_limit: f64,
+}
+
+impl ResourceCounters {
+    fn new(host: bool) -> Self {
+        let system = System::new_with_specifics(
+            RefreshKind::new().with_cpu(CpuRefreshKind::new().with_cpu_usage()),
+        );
+        let cpu_count = system.cpus().len().max(1) as f64;
+        let mut counters = Self {
+            system,
+            host,
+            pid: sysinfo::get_current_pid().ok(),
+            cpu_limit: cgroup_cpu_limit().map_or(cpu_count, |limit| limit.min(cpu_count)),
+        };
+        // CPU usage is computed from the difference to the previous refresh.
+        counters.refresh();
+        counters
+    }
+
+    fn refresh(&mut self) {
+        if self.host {
+            self.system.refresh_specifics(
+                RefreshKind::new()
+                    .with_cpu(CpuRefreshKind::new().with_cpu_usage())
+                    .with_memory(MemoryRefreshKind::new().with_ram()),
+            );
+        } else if let Some(pid) = self.pid {
+            self.system
+                .refresh_process_specifics(pid, ProcessRefreshKind::new().with_cpu().with_memory());
+        }
+    }
+
+    fn collect(&mut self) -> Vec<QuickPulseMetric> {
+        self.refresh();
+        let (cpu_usage, memory) = if self.host {
+            // Inside of a container, memory usage of the host would include all other containers.
+            let memory = self.system.cgroup_limits().map_or_else(
+                || self.system.used_memory(),
+                |limits| limits.total_memory.saturating_sub(limits.free_memory),
+            );
+            (f64::from(self.system.global_cpu_info().cpu_usage()), memory)
+        } else {
+            match self.pid.and_then(|pid| self.system.process(pid)) {
+                Some(process) => (
+                    f64::from(process.cpu_usage()) / self.cpu_limit,
+                    private_bytes().unwrap_or_else(|| process.memory()),
+                ),
+                None => return Vec::new(),
+            }
+        };
+        vec![
+            QuickPulseMetric {
+                name: METRIC_PROCESSOR_TIME.into(),
+                value: cpu_usage,
+                weight: 1,
+            },
+            QuickPulseMetric {
+                name: METRIC_COMMITTED_BYTES.into(),
+                value: memory as f64,
+                weight: 1,
+            },
+        ]
+    }
+}
+
+/// CPU quota of the cgroup of the process in number of cores, if there is one.
+#[cfg(target_os = "linux")]
+fn cgroup_cpu_limit() -> Option<f64> {
+    if let Ok(cpu_max) = std::fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        return parse_cgroup_v2_cpu_max(&cpu_max);
+    }
+    let quota = std::fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?;
+    let period = std::fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?;
+    parse_cgroup_cpu_quota(quota.trim(), period.trim())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_cpu_limit() -> Option<f64> {
+    None
+}
+
+/// Parses `cpu.max` of cgroup v2, which contains quota and period, e.g. `200000 100000`. The quota
+/// is `max` if there is no limit.
+#[cfg(any(target_os = "linux", test))]
+fn parse_cgroup_v2_cpu_max(cpu_max: &str) -> Option<f64> {
+    let mut parts = cpu_max.split_whitespace();
+    parse_cgroup_cpu_quota(parts.next()?, parts.next()?)
+}
+
+#[cfg(any(target_os = "linux", test))]
+fn parse_cgroup_cpu_quota(quota: &str, period: &str) -> Option<f64> {
+    // cgroup v1 uses -1 if there is no limit.
+    let quota = quota.parse::<f64>().ok().filter(|quota| *quota > 0.)?;
+    let period = period.parse::<f64>().ok().filter(|period| *period > 0.)?;
+    Some(quota / period)
+}
+
+struct MetricsCollector {
     request_count: usize,
     request_failed_count: usize,
     request_duration: Duration,
@@ -270,16 +1068,16 @@ struct MetricsCollector {
     dependency_failed_count: usize,
     dependency_duration: Duration,
     exception_count: usize,
+    documents: Vec<QuickPulseDocument>,
+    configuration: CollectionConfiguration,
+    #[cfg(feature = "metrics")]
+    custom_metrics: BTreeMap<String, LiveMetricValue>,
     last_collection_time: SystemTime,
 }
 
 impl MetricsCollector {
     fn new() -> Self {
         Self {
-            system: System::new(),
-            system_refresh_kind: RefreshKind::new()
-                .with_cpu(CpuRefreshKind::new().with_cpu_usage())
-                .with_memory(MemoryRefreshKind::new().with_ram()),
             request_count: 0,
             request_failed_count: 0,
             request_duration: Duration::default(),
@@ -287,6 +1085,10 @@ impl MetricsCollector {
             dependency_failed_count: 0,
             dependency_duration: Duration::default(),
             exception_count: 0,
+            documents: Vec::new(),
+            configuration: CollectionConfiguration::default(),
+            #[cfg(feature = "metrics")]
+            custom_metrics: BTreeMap::new(),
             last_collection_time: SystemTime::now(),
         }
     }
@@ -299,9 +1101,18 @@ impl MetricsCollector {
         self.dependency_failed_count = 0;
         self.dependency_duration = Duration::default();
         self.exception_count = 0;
+        self.documents.clear();
+        self.configuration.collect_and_reset();
+        #[cfg(feature = "metrics")]
+        self.custom_metrics.clear();
         self.last_collection_time = SystemTime::now();
     }
 
+    fn set_configuration(&mut self, configuration: CollectionConfiguration) {
+        self.configuration = configuration;
+        self.documents.clear();
+    }
+
     fn count_span(&mut self, span: SpanData) {
         // https://github.com/microsoft/ApplicationInsights-node.js/blob/aaafbfd8ffbc454d4a5c30cda4492891410b9f66/TelemetryProcessors/PerformanceMetricsTelemetryProcessor.ts#L6
         match span.span_kind {
@@ -309,53 +1120,141 @@ impl MetricsCollector {
                 self.request_count += 1;
                 if !is_request_success(&span) {
                     self.request_failed_count += 1;
+                    self.add_telemetry(TelemetryType::Request, true, || request_document(&span));
+                } else {
+                    self.add_telemetry(TelemetryType::Request, false, || request_document(&span));
                 }
-                self.request_duration += get_duration(&span);
+                let duration = get_duration(&span);
+                self.request_duration += duration;
             }
             SpanKind::Client | SpanKind::Producer | SpanKind::Internal => {
                 self.dependency_count += 1;
                 if let Some(false) = is_remote_dependency_success(&span) {
                     self.dependency_failed_count += 1;
+                    self.add_telemetry(TelemetryType::Dependency, true, || {
+                        remote_dependency_document(&span)
+                    });
+                } else {
+                    self.add_telemetry(TelemetryType::Dependency, false, || {
+                        remote_dependency_document(&span)
+                    });
                 }
-                self.dependency_duration += get_duration(&span);
+                let duration = get_duration(&span);
+                self.dependency_duration += duration;
             }
         }
 
         for event in span.events.iter() {
-            if event.name == EVENT_NAME_EXCEPTION {
-                self.exception_count += 1;
+            match event.name.as_ref() {
+                x if x == EVENT_NAME_EXCEPTION => {
+                    self.exception_count += 1;
+                    self.add_telemetry(TelemetryType::Exception, true, || {
+                        exception_document(&span, event)
+                    });
+                }
+                x if x == EVENT_NAME_CUSTOM => {}
+                _ => {
+                    self.add_telemetry(TelemetryType::Trace, true, || trace_document(&span, event))
+                }
             }
         }
     }
 
-    fn collect_and_reset(&mut self) -> Vec<QuickPulseMetric> {
-        let mut metrics = Vec::new();
-        self.system.refresh_specifics(self.system_refresh_kind);
-        self.collect_cpu_usage(&mut metrics);
-        self.collect_memory_usage(&mut metrics);
-        self.collect_requests_dependencies_exceptions(&mut metrics);
-        self.reset();
-        metrics
+    #[cfg(feature = "live-metrics-logs")]
+    fn count_log(&mut self, data: LogData) {
+        let is_exception = data.record.attributes.iter().flatten().any(|(key, _)| {
+            key.as_str() == semcov::trace::EXCEPTION_TYPE
+                || key.as_str() == semcov::trace::EXCEPTION_MESSAGE
+        });
+        if is_exception {
+            self.exception_count += 1;
+            self.add_telemetry(TelemetryType::Exception, true, || {
+                log_exception_document(&data.record)
+            });
+        } else {
+            self.add_telemetry(TelemetryType::Trace, true, || {
+                log_trace_document(&data.record)
+            });
+        }
     }
 
-    fn collect_cpu_usage(&mut self, metrics: &mut Vec<QuickPulseMetric>) {
-        let mut cpu_usage = 0.;
-        for cpu in self.system.cpus() {
-            cpu_usage += f64::from(cpu.cpu_usage());
+    #[cfg(feature = "metrics")]
+    fn record_metric(&mut self, name: &str, value: LiveMetricValue) {
+        match self.custom_metrics.get_mut(name) {
+            Some(existing) => *existing = existing.merge(value),
+            None => {
+                self.custom_metrics.insert(name.into(), value);
+            }
         }
-        metrics.push(QuickPulseMetric {
-            name: METRIC_PROCESSOR_TIME,
-            value: cpu_usage,
-            weight: 1,
-        });
     }
 
-    fn collect_memory_usage(&mut self, metrics: &mut Vec<QuickPulseMetric>) {
-        metrics.push(QuickPulseMetric {
-            name: METRIC_COMMITTED_BYTES,
-            value: self.system.used_memory() as f64,
-            weight: 1,
-        });
+    /// Records telemetry in the custom charts of the collection configuration and buffers it as a
+    /// sample telemetry document for the next post, unless the limit for this post is already
+    /// reached.
+    ///
+    /// Without document streams in the configuration, only failed requests and dependencies,
+    /// exceptions and traces (`is_default_sample`) are sampled.
+    ///
+    /// The document is only built if a custom chart or a sample telemetry filter may use it.
+    fn add_telemetry(
+        &mut self,
+        telemetry_type: TelemetryType,
+        is_default_sample: bool,
+        document: impl FnOnce() -> QuickPulseDocument,
+    ) {
+        let is_document_buffer_full = self.documents.len() >= MAX_DOCUMENTS_PER_POST;
+        if self.configuration.is_empty() {
+            if is_default_sample && !is_document_buffer_full {
+                self.documents.push(document());
+            }
+            return;
+        }
+
+        let has_metrics = self.configuration.has_metrics_for(telemetry_type);
+        let may_sample = !is_document_buffer_full
+            && if self.configuration.has_document_streams() {
+                self.configuration.has_document_streams_for(telemetry_type)
+            } else {
+                is_default_sample
+            };
+        if !has_metrics && !may_sample {
+            return;
+        }
+        let mut document = document();
+        if has_metrics {
+            self.configuration.record(&document);
+        }
+        if !may_sample {
+            return;
+        }
+        if self.configuration.has_document_streams() {
+            document.document_stream_ids = self.configuration.matching_document_streams(&document);
+            if !document.document_stream_ids.is_empty() {
+                self.documents.push(document);
+            }
+        } else if is_default_sample {
+            self.documents.push(document);
+        }
+    }
+
+    fn take_documents(&mut self) -> Vec<QuickPulseDocument> {
+        std::mem::take(&mut self.documents)
+    }
+
+    fn collect_and_reset(&mut self) -> Vec<QuickPulseMetric> {
+        let mut metrics = Vec::new();
+        self.collect_requests_dependencies_exceptions(&mut metrics);
+        metrics.extend(self.configuration.collect_and_reset());
+        #[cfg(feature = "metrics")]
+        metrics.extend(self.custom_metrics.iter().filter_map(|(name, value)| {
+            Some(QuickPulseMetric {
+                name: name.clone().into(),
+                value: value.value()?,
+                weight: 1,
+            })
+        }));
+        self.reset();
+        metrics
     }
 
     fn collect_requests_dependencies_exceptions(&mut self, metrics: &mut Vec<QuickPulseMetric>) {
@@ -368,45 +1267,435 @@ impl MetricsCollector {
         }
 
         metrics.push(QuickPulseMetric {
-            name: METRIC_REQUEST_RATE,
+            name: METRIC_REQUEST_RATE.into(),
             value: self.request_count as f64 / elapsed_seconds as f64,
             weight: 1,
         });
         metrics.push(QuickPulseMetric {
-            name: METRIC_REQUEST_FAILURE_RATE,
+            name: METRIC_REQUEST_FAILURE_RATE.into(),
             value: self.request_failed_count as f64 / elapsed_seconds as f64,
             weight: 1,
         });
         if self.request_count > 0 {
             metrics.push(QuickPulseMetric {
-                name: METRIC_REQUEST_DURATION,
+                name: METRIC_REQUEST_DURATION.into(),
                 value: self.request_duration.as_millis() as f64 / self.request_count as f64,
                 weight: 1,
             });
         }
 
         metrics.push(QuickPulseMetric {
-            name: METRIC_DEPENDENCY_RATE,
+            name: METRIC_DEPENDENCY_RATE.into(),
             value: self.dependency_count as f64 / elapsed_seconds as f64,
             weight: 1,
         });
         metrics.push(QuickPulseMetric {
-            name: METRIC_DEPENDENCY_FAILURE_RATE,
+            name: METRIC_DEPENDENCY_FAILURE_RATE.into(),
             value: self.dependency_failed_count as f64 / elapsed_seconds as f64,
             weight: 1,
         });
         if self.dependency_count > 0 {
             metrics.push(QuickPulseMetric {
-                name: METRIC_DEPENDENCY_DURATION,
+                name: METRIC_DEPENDENCY_DURATION.into(),
                 value: self.dependency_duration.as_millis() as f64 / self.dependency_count as f64,
                 weight: 1,
             });
         }
 
         metrics.push(QuickPulseMetric {
-            name: METRIC_EXCEPTION_RATE,
+            name: METRIC_EXCEPTION_RATE.into(),
             value: self.exception_count as f64 / elapsed_seconds as f64,
             weight: 1,
         });
     }
 }
+
+fn request_document(span: &SpanData) -> QuickPulseDocument {
+    let data = RequestData::from(span);
+    QuickPulseDocument::new(
+        Some(format!("{}", span.span_context.trace_id())),
+        document_properties(data.properties),
+        QuickPulseDocumentData::Request {
+            name: data.name.map(String::from).unwrap_or_default(),
+            success: data.success,
+            duration: data.duration,
+            response_code: data.response_code.into(),
+            url: data.url.map(Into::into),
+            operation_name: operation_name(span),
+        },
+    )
+}
+
+fn remote_dependency_document(span: &SpanData) -> QuickPulseDocument {
+    let data = RemoteDependencyData::from(span);
+    QuickPulseDocument::new(
+        Some(format!("{}", span.span_context.trace_id())),
+        document_properties(data.properties),
+        QuickPulseDocumentData::RemoteDependency {
+            name: data.name.into(),
+            success: data.success,
+            duration: data.duration,
+            result_code: data.result_code.map(Into::into),
+            target: data.target.map(Into::into),
+            command_name: data.data.map(Into::into),
+            dependency_type_name: data.type_.map(Into::into),
+            operation_name: operation_name(span),
+        },
+    )
+}
+
+fn exception_document(span: &SpanData, event: &Event) -> QuickPulseDocument {
+    let mut data = ExceptionData::from(event);
+    let exception = data.exceptions.remove(0);
+    let message: String = exception.message.into();
+    QuickPulseDocument::new(
+        Some(format!("{}", span.span_context.trace_id())),
+        document_properties(data.properties),
+        QuickPulseDocumentData::Exception {
+            exception: exception.stack.map_or_else(|| message.clone(), Into::into),
+            exception_message: message,
+            exception_type: exception.type_name.into(),
+        },
+    )
+}
+
+fn trace_document(span: &SpanData, event: &Event) -> QuickPulseDocument {
+    let data = MessageData::from(event);
+    QuickPulseDocument::new(
+        Some(format!("{}", span.span_context.trace_id())),
+        document_properties(data.properties),
+        QuickPulseDocumentData::Trace {
+            message: data.message.into(),
+            severity_level: data.severity_level.map(|level| match level {
+                SeverityLevel::Verbose => "Verbose",
+                SeverityLevel::Information => "Information",
+                SeverityLevel::Warning => "Warning",
+                SeverityLevel::Error => "Error",
+            }),
+        },
+    )
+}
+
+#[cfg(feature = "live-metrics-logs")]
+fn log_exception_document(record: &LogRecord) -> QuickPulseDocument {
+    let mut message = None;
+    let mut type_name = None;
+    let mut stack = None;
+    let mut properties = Vec::new();
+    for (key, value) in record.attributes.iter().flatten() {
+        let value = any_value_to_string(value);
+        match key.as_str() {
+            semcov::trace::EXCEPTION_MESSAGE => message = Some(value),
+            semcov::trace::EXCEPTION_TYPE => type_name = Some(value),
+            semcov::trace::EXCEPTION_STACKTRACE => stack = Some(value),
+            _ => properties.push(QuickPulseDocumentProperty {
+                key: key.as_str().into(),
+                value,
+            }),
+        }
+    }
+    let message = message.unwrap_or_else(|| "<no message>".into());
+    QuickPulseDocument::new(
+        log_operation_id(record),
+        properties,
+        QuickPulseDocumentData::Exception {
+            exception: stack.unwrap_or_else(|| message.clone()),
+            exception_message: message,
+            exception_type: type_name.unwrap_or_else(|| "<no type>".into()),
+        },
+    )
+}
+
+#[cfg(feature = "live-metrics-logs")]
+fn log_trace_document(record: &LogRecord) -> QuickPulseDocument {
+    QuickPulseDocument::new(
+        log_operation_id(record),
+        record
+            .attributes
+            .iter()
+            .flatten()
+            .map(|(key, value)| QuickPulseDocumentProperty {
+                key: key.as_str().into(),
+                value: any_value_to_string(value),
+            })
+            .collect(),
+        QuickPulseDocumentData::Trace {
+            message: record
+                .body
+                .as_ref()
+                .map(any_value_to_string)
+                .unwrap_or_else(|| "<no message>".into()),
+            severity_level: record.severity_number.map(|severity| {
+                if severity >= Severity::Fatal {
+                    "Critical"
+                } else if severity >= Severity::Error {
+                    "Error"
+                } else if severity >= Severity::Warn {
+                    "Warning"
+                } else if severity >= Severity::Info {
+                    "Information"
+                } else {
+                    "Verbose"
+                }
+            }),
+        },
+    )
+}
+
+#[cfg(feature = "live-metrics-logs")]
+fn log_operation_id(record: &LogRecord) -> Option<String> {
+    record
+        .trace_context
+        .as_ref()
+        .map(|trace_context| format!("{}", trace_context.trace_id))
+}
+
+#[cfg(feature = "live-metrics-logs")]
+fn any_value_to_string(value: &AnyValue) -> String {
+    match value {
+        AnyValue::Int(value) => value.to_string(),
+        AnyValue::Double(value) => value.to_string(),
+        AnyValue::String(value) => value.as_str().into(),
+        AnyValue::Boolean(value) => value.to_string(),
+        AnyValue::Bytes(_) | AnyValue::ListAny(_) | AnyValue::Map(_) => {
+            any_value_to_json(value).to_string()
+        }
+    }
+}
+
+#[cfg(feature = "live-metrics-logs")]
+fn any_value_to_json(value: &AnyValue) -> serde_json::Value {
+    match value {
+        AnyValue::Int(value) => (*value).into(),
+        AnyValue::Double(value) => (*value).into(),
+        AnyValue::String(value) => value.as_str().into(),
+        AnyValue::Boolean(value) => (*value).into(),
+        AnyValue::Bytes(value) => value.clone().into(),
+        AnyValue::ListAny(values) => values.iter().map(any_value_to_json).collect(),
+        AnyValue::Map(map) => map
+            .iter()
+            .map(|(key, value)| (key.as_str().to_string(), any_value_to_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    }
+}
+
+fn operation_name(span: &SpanData) -> Option<String> {
+    get_tags_for_span(span, &[]).remove(context_tag_keys::OPERATION_NAME)
+}
+
+fn document_properties(properties: Option<Properties>) -> Vec<QuickPulseDocumentProperty> {
+    properties
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| QuickPulseDocumentProperty {
+            key: key.into(),
+            value: value.into(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{
+        trace::{SpanContext, SpanId, Status},
+        InstrumentationLibrary,
+    };
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+    use test_case::test_case;
+
+    #[derive(Debug)]
+    struct AsyncOnlyClient;
+
+    #[async_trait::async_trait]
+    impl HttpClient for AsyncOnlyClient {
+        async fn send(
+            &self,
+            _request: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<bytes::Bytes>, opentelemetry_http::HttpError> {
+            panic!("there is no reactor running")
+        }
+    }
+
+    #[test]
+    fn thread_manager_stops_when_client_panics() {
+        let collector = QuickPulseCollector::default();
+        let status = collector.status();
+        let mut manager = QuickPulseThreadManager::new(
+            Arc::new(AsyncOnlyClient),
+            "https://live.example.com".parse().unwrap(),
+            "ikey".into(),
+            Resource::empty(),
+            collector,
+            LiveMetricsConfig::new().with_ping_interval(Duration::from_millis(10)),
+        );
+        for _ in 0..100 {
+            if status.state() == LiveMetricsState::Idle {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(LiveMetricsState::Idle, status.state());
+        assert!(manager.shutdown().is_err());
+    }
+
+    #[derive(Debug)]
+    struct FailingClient;
+
+    #[async_trait::async_trait]
+    impl HttpClient for FailingClient {
+        async fn send(
+            &self,
+            _request: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<bytes::Bytes>, opentelemetry_http::HttpError> {
+            Err("connection refused".into())
+        }
+    }
+
+    #[test]
+    fn back_off_after_failed_pings() {
+        let collector = QuickPulseCollector::default();
+        let status = collector.status();
+        let mut worker = QuickPulseWorker::new(
+            Arc::new(FailingClient),
+            "https://live.example.com".parse().unwrap(),
+            "ikey".into(),
+            Resource::empty(),
+            collector,
+            LiveMetricsConfig::new()
+                .with_max_ping_wait_time(Duration::ZERO)
+                .with_fallback_interval(Duration::from_secs(30)),
+        );
+        assert_eq!(LiveMetricsState::Pinging, status.state());
+        let delay = futures_executor::block_on(worker.send());
+        assert_eq!(LiveMetricsState::BackedOff, status.state());
+        assert_eq!(Duration::from_secs(30), delay);
+    }
+
+    #[derive(Debug)]
+    struct InvalidConfigurationClient;
+
+    #[async_trait::async_trait]
+    impl HttpClient for InvalidConfigurationClient {
+        async fn send(
+            &self,
+            _request: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<bytes::Bytes>, opentelemetry_http::HttpError> {
+            Ok(http::Response::builder()
+                .header("x-ms-qps-configuration-etag", "etag")
+                .body(bytes::Bytes::from_static(b"not json"))
+                .unwrap())
+        }
+    }
+
+    #[test]
+    fn store_etag_of_invalid_configuration() {
+        let mut worker = QuickPulseWorker::new(
+            Arc::new(InvalidConfigurationClient),
+            "https://live.example.com".parse().unwrap(),
+            "ikey".into(),
+            Resource::empty(),
+            QuickPulseCollector::default(),
+            LiveMetricsConfig::new(),
+        );
+        futures_executor::block_on(worker.send());
+        assert_eq!("etag", worker.sender.configuration_etag);
+    }
+
+    #[test]
+    fn build_documents_only_for_configured_telemetry_types() {
+        let mut collector = MetricsCollector::new();
+        collector.set_configuration(CollectionConfiguration::new(
+            serde_json::from_str(
+                r#"{
+                    "Metrics": [
+                        {
+                            "Id": "requests",
+                            "TelemetryType": "Request",
+                            "FilterGroups": [],
+                            "Projection": "Count()",
+                            "Aggregation": "Sum"
+                        }
+                    ]
+                }"#,
+            )
+            .unwrap(),
+        ));
+        collector.add_telemetry(TelemetryType::Dependency, false, || {
+            panic!("document without matching metric or stream is built")
+        });
+        let mut built = false;
+        collector.add_telemetry(TelemetryType::Request, false, || {
+            built = true;
+            request_document(&SpanData {
+                span_context: SpanContext::empty_context(),
+                parent_span_id: SpanId::INVALID,
+                span_kind: SpanKind::Server,
+                name: "request".into(),
+                start_time: SystemTime::UNIX_EPOCH,
+                end_time: SystemTime::UNIX_EPOCH,
+                attributes: Vec::new(),
+                dropped_attributes_count: 0,
+                events: SpanEvents::default(),
+                links: SpanLinks::default(),
+                status: Status::Unset,
+                resource: Cow::Owned(Resource::empty()),
+                instrumentation_lib: InstrumentationLibrary::default(),
+            })
+        });
+        assert!(built);
+        assert!(collector.take_documents().is_empty());
+    }
+
+    #[test]
+    fn remove_panicking_counters_and_reject_built_in_names() {
+        let collector = QuickPulseCollector::default();
+        collector.add_counter("Queue depth".into(), Arc::new(|| 42.));
+        collector.add_counter("Panics".into(), Arc::new(|| panic!("counter failed")));
+        collector.add_counter(METRIC_REQUEST_RATE.into(), Arc::new(|| 1000.));
+        for _ in 0..2 {
+            let metrics: Vec<_> = collector
+                .sample_counters()
+                .into_iter()
+                .map(|metric| (metric.name, metric.value))
+                .collect();
+            assert_eq!(vec![("Queue depth".into(), 42.)], metrics);
+        }
+        assert_eq!(1, collector.counters.lock().unwrap().len());
+    }
+
+    #[test]
+    fn ignore_zero_intervals() {
+        let config = LiveMetricsConfig::new()
+            .with_ping_interval(Duration::ZERO)
+            .with_post_interval(Duration::ZERO)
+            .with_fallback_interval(Duration::ZERO);
+        assert_eq!(DEFAULT_PING_INTERVAL, config.ping_interval);
+        assert_eq!(DEFAULT_POST_INTERVAL, config.post_interval);
+        assert_eq!(DEFAULT_FALLBACK_INTERVAL, config.fallback_interval);
+    }
+
+    #[test_case("200000 100000", Some(2.) ; "two cores")]
+    #[test_case("50000 100000", Some(0.5) ; "half a core")]
+    #[test_case("max 100000", None ; "unlimited")]
+    #[test_case("", None ; "empty")]
+    fn cgroup_v2_cpu_max(cpu_max: &str, expected: Option<f64>) {
+        assert_eq!(expected, parse_cgroup_v2_cpu_max(cpu_max));
+    }
+
+    #[test_case("150000", "100000", Some(1.5) ; "limited")]
+    #[test_case("-1", "100000", None ; "unlimited")]
+    fn cgroup_v1_cpu_quota(quota: &str, period: &str, expected: Option<f64>) {
+        assert_eq!(expected, parse_cgroup_cpu_quota(quota, period));
+    }
+
+    #[test]
+    fn process_resource_counters() {
+        let mut counters = ResourceCounters::new(false);
+        let metrics = counters.collect();
+        let names: Vec<_> = metrics.iter().map(|metric| metric.name.as_ref()).collect();
+        assert_eq!(vec![METRIC_PROCESSOR_TIME, METRIC_COMMITTED_BYTES], names);
+        assert!(metrics[1].value > 0.);
+    }
+}
@@ -0,0 +1,684 @@
+use crate::{
+    models::{
+        CollectionConfigurationInfo, DerivedMetricInfo, DocumentStreamInfo, FilterInfo,
+        QuickPulseDocument, QuickPulseDocumentData, QuickPulseMetric,
+    },
+    Error,
+};
+use opentelemetry::trace::TraceError;
+use std::{borrow::Cow, cmp::Ordering};
+
+/// Custom charts and sample telemetry filters configured in the Live Metrics portal.
+///
+/// Application Insights sends the configuration in ping and post responses whenever its ETag
+/// changes. Invalid metrics and document streams are reported through the global error handler
+/// and ignored.
+#[derive(Debug, Default)]
+pub(crate) struct CollectionConfiguration {
+    metrics: Vec<DerivedMetric>,
+    document_streams: Vec<DocumentStream>,
+}
+
+impl CollectionConfiguration {
+    pub(crate) fn new(info: CollectionConfigurationInfo) -> Self {
+        let metrics = info
+            .metrics
+            .into_iter()
+            .filter_map(|info| report_invalid(DerivedMetric::new(info)))
+            .collect();
+        let document_streams = info
+            .document_streams
+            .into_iter()
+            .filter_map(|info| report_invalid(DocumentStream::new(info)))
+            .collect();
+        Self {
+            metrics,
+            document_streams,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.metrics.is_empty() && self.document_streams.is_empty()
+    }
+
+    pub(crate) fn has_document_streams(&self) -> bool {
+        !self.document_streams.is_empty()
+    }
+
+    /// Whether any derived metric records telemetry of this type.
+    pub(crate) fn has_metrics_for(&self, telemetry_type: TelemetryType) -> bool {
+        self.metrics
+            .iter()
+            .any(|metric| metric.telemetry_type == telemetry_type)
+    }
+
+    /// Whether any document stream has a filter group for telemetry of this type.
+    pub(crate) fn has_document_streams_for(&self, telemetry_type: TelemetryType) -> bool {
+        self.document_streams.iter().any(|stream| {
+            stream
+                .filter_groups
+                .iter()
+                .any(|group| group.telemetry_type == telemetry_type)
+        })
+    }
+
+    /// Records the document in all derived metrics it matches.
+    pub(crate) fn record(&mut self, document: &QuickPulseDocument) {
+        for metric in self.metrics.iter_mut() {
+            metric.record(document);
+        }
+    }
+
+    /// Ids of all document streams the document matches.
+    pub(crate) fn matching_document_streams(&self, document: &QuickPulseDocument) -> Vec<String> {
+        self.document_streams
+            .iter()
+            .filter(|stream| stream.matches(document))
+            .map(|stream| stream.id.clone())
+            .collect()
+    }
+
+    /// Values of the derived metrics since the last collection.
+    pub(crate) fn collect_and_reset(&mut self) -> Vec<QuickPulseMetric> {
+        self.metrics
+            .iter_mut()
+            .filter_map(DerivedMetric::collect_and_reset)
+            .collect()
+    }
+}
+
+fn report_invalid<T>(result: Result<T, String>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            opentelemetry::global::handle_error(TraceError::from(
+                Error::QuickPulseCollectionConfiguration(err),
+            ));
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TelemetryType {
+    Request,
+    Dependency,
+    Exception,
+    Trace,
+}
+
+impl TelemetryType {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "Request" => Ok(TelemetryType::Request),
+            "Dependency" | "RemoteDependency" => Ok(TelemetryType::Dependency),
+            "Exception" => Ok(TelemetryType::Exception),
+            "Trace" => Ok(TelemetryType::Trace),
+            _ => Err(format!("unsupported telemetry type {:?}", value)),
+        }
+    }
+
+    fn of(document: &QuickPulseDocument) -> Self {
+        match document.data {
+            QuickPulseDocumentData::Request { .. } => TelemetryType::Request,
+            QuickPulseDocumentData::RemoteDependency { .. } => TelemetryType::Dependency,
+            QuickPulseDocumentData::Exception { .. } => TelemetryType::Exception,
+            QuickPulseDocumentData::Trace { .. } => TelemetryType::Trace,
+        }
+    }
+
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            TelemetryType::Request => &["Name", "Success", "Duration", "ResponseCode", "Url"],
+            TelemetryType::Dependency => &[
+                "Name",
+                "Success",
+                "Duration",
+                "ResultCode",
+                "Target",
+                "Type",
+                "Data",
+            ],
+            TelemetryType::Exception => &["Exception.Message", "Exception.Type"],
+            TelemetryType::Trace => &["Message", "SeverityLevel"],
+        }
+    }
+}
+
+const ANY_FIELD: &str = "*";
+const CUSTOM_DIMENSIONS_PREFIX: &str = "CustomDimensions.";
+
+/// Value of a field of a document, as it's used in filters and projections. Durations are in
+/// milliseconds.
+fn field_value<'a>(document: &'a QuickPulseDocument, field_name: &str) -> Option<Cow<'a, str>> {
+    if let Some(key) = field_name.strip_prefix(CUSTOM_DIMENSIONS_PREFIX) {
+        return document
+            .properties
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str().into());
+    }
+
+    let value: Cow<'a, str> = match (&document.data, field_name) {
+        (QuickPulseDocumentData::Request { name, .. }, "Name") => name.into(),
+        (QuickPulseDocumentData::Request { success, .. }, "Success") => success.to_string().into(),
+        (QuickPulseDocumentData::Request { duration, .. }, "Duration") => {
+            timespan_to_ms(duration)?.to_string().into()
+        }
+        (QuickPulseDocumentData::Request { response_code, .. }, "ResponseCode") => {
+            response_code.into()
+        }
+        (QuickPulseDocumentData::Request { url, .. }, "Url") => url.as_deref()?.into(),
+        (QuickPulseDocumentData::RemoteDependency { name, .. }, "Name") => name.into(),
+        (QuickPulseDocumentData::RemoteDependency { success, .. }, "Success") => {
+            success.unwrap_or(true).to_string().into()
+        }
+        (QuickPulseDocumentData::RemoteDependency { duration, .. }, "Duration") => {
+            timespan_to_ms(duration)?.to_string().into()
+        }
+        (QuickPulseDocumentData::RemoteDependency { result_code, .. }, "ResultCode") => {
+            result_code.as_deref()?.into()
+        }
+        (QuickPulseDocumentData::RemoteDependency { target, .. }, "Target") => {
+            target.as_deref()?.into()
+        }
+        (
+            QuickPulseDocumentData::RemoteDependency {
+                dependency_type_name,
+                ..
+            },
+            "Type",
+        ) => dependency_type_name.as_deref()?.into(),
+        (QuickPulseDocumentData::RemoteDependency { command_name, .. }, "Data") => {
+            command_name.as_deref()?.into()
+        }
+        (
+            QuickPulseDocumentData::Exception {
+                exception_message, ..
+            },
+            "Exception.Message",
+        ) => exception_message.into(),
+        (QuickPulseDocumentData::Exception { exception_type, .. }, "Exception.Type") => {
+            exception_type.into()
+        }
+        (QuickPulseDocumentData::Trace { message, .. }, "Message") => message.into(),
+        (QuickPulseDocumentData::Trace { severity_level, .. }, "SeverityLevel") => {
+            (*severity_level)?.into()
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Parses a .NET `TimeSpan` like `0.00:00:01.500000` or `00:00:01.5` into milliseconds.
+fn timespan_to_ms(value: &str) -> Option<f64> {
+    let mut parts = value.splitn(3, ':');
+    let (days_hours, minutes, seconds) = (parts.next()?, parts.next()?, parts.next()?);
+    let (days, hours) = match days_hours.split_once('.') {
+        Some((days, hours)) => (days.parse::<f64>().ok()?, hours),
+        None => (0., days_hours),
+    };
+    let hours = hours.parse::<f64>().ok()?;
+    let minutes = minutes.parse::<f64>().ok()?;
+    let seconds = seconds.parse::<f64>().ok()?;
+    Some((((days * 24. + hours) * 60. + minutes) * 60. + seconds) * 1000.)
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    value.parse().ok().or_else(|| timespan_to_ms(value))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Predicate {
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    LessThanOrEqual,
+    GreaterThanOrEqual,
+    Contains,
+    DoesNotContain,
+}
+
+#[derive(Debug)]
+struct Filter {
+    field_name: String,
+    predicate: Predicate,
+    comparand: String,
+    comparand_number: Option<f64>,
+}
+
+impl Filter {
+    fn new(info: FilterInfo, telemetry_type: TelemetryType) -> Result<Self, String> {
+        let predicate = match info.predicate.as_str() {
+            "Equal" => Predicate::Equal,
+            "NotEqual" => Predicate::NotEqual,
+            "LessThan" => Predicate::LessThan,
+            "GreaterThan" => Predicate::GreaterThan,
+            "LessThanOrEqual" => Predicate::LessThanOrEqual,
+            "GreaterThanOrEqual" => Predicate::GreaterThanOrEqual,
+            "Contains" => Predicate::Contains,
+            "DoesNotContain" => Predicate::DoesNotContain,
+            _ => return Err(format!("unsupported filter predicate {:?}", info.predicate)),
+        };
+        let comparand_number = parse_number(&info.comparand);
+        if info.field_name == ANY_FIELD {
+            if !matches!(predicate, Predicate::Contains | Predicate::DoesNotContain) {
+                return Err(format!(
+                    "filter on any field only supports Contains and DoesNotContain, not {:?}",
+                    info.predicate
+                ));
+            }
+        } else if !info.field_name.starts_with(CUSTOM_DIMENSIONS_PREFIX)
+            && !telemetry_type.fields().contains(&info.field_name.as_str())
+        {
+            return Err(format!(
+                "unsupported field {:?} for {:?} telemetry",
+                info.field_name, telemetry_type
+            ));
+        }
+        if matches!(
+            predicate,
+            Predicate::LessThan
+                | Predicate::GreaterThan
+                | Predicate::LessThanOrEqual
+                | Predicate::GreaterThanOrEqual
+        ) && comparand_number.is_none()
+        {
+            return Err(format!(
+                "comparand {:?} of filter on {:?} is not a number",
+                info.comparand, info.field_name
+            ));
+        }
+        Ok(Self {
+            field_name: info.field_name,
+            predicate,
+            comparand: info.comparand.to_lowercase(),
+            comparand_number,
+        })
+    }
+
+    fn matches(&self, document: &QuickPulseDocument) -> bool {
+        if self.field_name == ANY_FIELD {
+            let telemetry_type = TelemetryType::of(document);
+            let contains = telemetry_type
+                .fields()
+                .iter()
+                .filter_map(|field_name| field_value(document, field_name))
+                .chain(
+                    document
+                        .properties
+                        .iter()
+                        .map(|property| property.value.as_str().into()),
+                )
+                .any(|value| value.to_lowercase().contains(&self.comparand));
+            return match self.predicate {
+                Predicate::DoesNotContain => !contains,
+                _ => contains,
+            };
+        }
+
+        let value = field_value(document, &self.field_name).unwrap_or_default();
+        let ordering = match (self.comparand_number, parse_number(&value)) {
+            (Some(comparand), Some(value)) => value.partial_cmp(&comparand),
+            _ => None,
+        };
+        let value = value.to_lowercase();
+        match self.predicate {
+            Predicate::Equal => ordering.map_or(value == self.comparand, Ordering::is_eq),
+            Predicate::NotEqual => ordering.map_or(value != self.comparand, Ordering::is_ne),
+            Predicate::LessThan => ordering.is_some_and(Ordering::is_lt),
+            Predicate::GreaterThan => ordering.is_some_and(Ordering::is_gt),
+            Predicate::LessThanOrEqual => ordering.is_some_and(Ordering::is_le),
+            Predicate::GreaterThanOrEqual => ordering.is_some_and(Ordering::is_ge),
+            Predicate::Contains => value.contains(&self.comparand),
+            Predicate::DoesNotContain => !value.contains(&self.comparand),
+        }
+    }
+}
+
+/// Filters which all have to match.
+#[derive(Debug)]
+struct FilterConjunction {
+    telemetry_type: TelemetryType,
+    filters: Vec<Filter>,
+}
+
+impl FilterConjunction {
+    fn new(telemetry_type: TelemetryType, filters: Vec<FilterInfo>) -> Result<Self, String> {
+        Ok(Self {
+            telemetry_type,
+            filters: filters
+                .into_iter()
+                .map(|info| Filter::new(info, telemetry_type))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn matches(&self, document: &QuickPulseDocument) -> bool {
+        TelemetryType::of(document) == self.telemetry_type
+            && self.filters.iter().all(|filter| filter.matches(document))
+    }
+}
+
+#[derive(Debug)]
+enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+}
+
+/// Custom chart: a value projected from matching telemetry and aggregated over one post interval.
+#[derive(Debug)]
+struct DerivedMetric {
+    id: String,
+    telemetry_type: TelemetryType,
+    filter_groups: Vec<FilterConjunction>,
+    projection: Option<String>,
+    aggregation: Aggregation,
+    count: usize,
+    value: f64,
+}
+
+impl DerivedMetric {
+    fn new(info: DerivedMetricInfo) -> Result<Self, String> {
+        let id = info.id.clone();
+        let invalid = |err: String| format!("metric {:?}: {}", id, err);
+        let telemetry_type = TelemetryType::parse(&info.telemetry_type).map_err(invalid)?;
+        let aggregation = match info.aggregation.as_str() {
+            "Avg" => Aggregation::Avg,
+            "Sum" => Aggregation::Sum,
+            "Min" => Aggregation::Min,
+            "Max" => Aggregation::Max,
+            _ => {
+                return Err(invalid(format!(
+                    "unsupported aggregation {:?}",
+                    info.aggregation
+                )))
+            }
+        };
+        let projection = match info.projection.as_str() {
+            "Count()" => None,
+            field_name
+                if field_name.starts_with(CUSTOM_DIMENSIONS_PREFIX)
+                    || telemetry_type.fields().contains(&field_name) =>
+            {
+                Some(info.projection.clone())
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "unsupported projection {:?}",
+                    info.projection
+                )))
+            }
+        };
+        let filter_groups = info
+            .filter_groups
+            .into_iter()
+            .map(|group| FilterConjunction::new(telemetry_type, group.filters))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+        Ok(Self {
+            id: info.id,
+            telemetry_type,
+            filter_groups,
+            projection,
+            aggregation,
+            count: 0,
+            value: 0.,
+        })
+    }
+
+    fn record(&mut self, document: &QuickPulseDocument) {
+        if TelemetryType::of(document) != self.telemetry_type
+            || !(self.filter_groups.is_empty()
+                || self
+                    .filter_groups
+                    .iter()
+                    .any(|group| group.matches(document)))
+        {
+            return;
+        }
+        let value = match &self.projection {
+            None => 1.,
+            Some(field_name) => match field_value(document, field_name)
+                .as_deref()
+                .and_then(parse_number)
+            {
+                Some(value) => value,
+                None => return,
+            },
+        };
+        self.value = match (self.count, &self.aggregation) {
+            (0, _) => value,
+            (_, Aggregation::Avg) | (_, Aggregation::Sum) => self.value + value,
+            (_, Aggregation::Min) => self.value.min(value),
+            (_, Aggregation::Max) => self.value.max(value),
+        };
+        self.count += 1;
+    }
+
+    fn collect_and_reset(&mut self) -> Option<QuickPulseMetric> {
+        let count = std::mem::take(&mut self.count);
+        let value = std::mem::take(&mut self.value);
+        let value = match self.aggregation {
+            Aggregation::Sum => value,
+            _ if count == 0 => return None,
+            Aggregation::Avg => value / count as f64,
+            Aggregation::Min | Aggregation::Max => value,
+        };
+        Some(QuickPulseMetric {
+            name: self.id.clone().into(),
+            value,
+            weight: 1,
+        })
+    }
+}
+
+/// Sample telemetry filter. A document is part of the stream if it matches any filter group.
+#[derive(Debug)]
+struct DocumentStream {
+    id: String,
+    filter_groups: Vec<FilterConjunction>,
+}
+
+impl DocumentStream {
+    fn new(info: DocumentStreamInfo) -> Result<Self, String> {
+        let id = info.id;
+        let filter_groups = info
+            .document_filter_groups
+            .into_iter()
+            .map(|group| {
+                FilterConjunction::new(
+                    TelemetryType::parse(&group.telemetry_type)?,
+                    group.filters.filters,
+                )
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("document stream {:?}: {}", id, err))?;
+        Ok(Self { id, filter_groups })
+    }
+
+    fn matches(&self, document: &QuickPulseDocument) -> bool {
+        self.filter_groups
+            .iter()
+            .any(|group| group.matches(document))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::QuickPulseDocumentProperty;
+    use test_case::test_case;
+
+    fn request(success: bool, duration: &str, route: &str) -> QuickPulseDocument {
+        QuickPulseDocument::new(
+            None,
+            vec![QuickPulseDocumentProperty {
+                key: "http.route".into(),
+                value: route.into(),
+            }],
+            QuickPulseDocumentData::Request {
+                name: format!("GET {}", route),
+                success,
+                duration: duration.into(),
+                response_code: if success { "200" } else { "500" }.into(),
+                url: None,
+                operation_name: None,
+            },
+        )
+    }
+
+    fn configuration(json: &str) -> CollectionConfiguration {
+        CollectionConfiguration::new(serde_json::from_str(json).expect("configuration is valid"))
+    }
+
+    #[test_case("0.00:00:01.500000", Some(1500.) ; "ai format")]
+    #[test_case("00:01:00", Some(60000.) ; "without days")]
+    #[test_case("1.00:00:00", Some(86400000.) ; "days")]
+    #[test_case("1500", None ; "number")]
+    fn parse_timespan(value: &str, expected: Option<f64>) {
+        assert_eq!(expected, timespan_to_ms(value));
+    }
+
+    #[test_case("Duration", "GreaterThan", "1000", true ; "number greater")]
+    #[test_case("Duration", "LessThanOrEqual", "00:00:01", false ; "timespan comparand")]
+    #[test_case("Success", "Equal", "False", true ; "bool case insensitive")]
+    #[test_case("ResponseCode", "NotEqual", "500", false ; "number not equal")]
+    #[test_case("Name", "Contains", "users", true ; "contains")]
+    #[test_case("Name", "DoesNotContain", "users", false ; "does not contain")]
+    #[test_case("CustomDimensions.http.route", "Equal", "/users", true ; "custom dimension")]
+    #[test_case("CustomDimensions.missing", "Equal", "/users", false ; "missing custom dimension")]
+    #[test_case("*", "Contains", "USERS", true ; "any field")]
+    fn filter_matches(field_name: &str, predicate: &str, comparand: &str, expected: bool) {
+        let filter = Filter::new(
+            FilterInfo {
+                field_name: field_name.into(),
+                predicate: predicate.into(),
+                comparand: comparand.into(),
+            },
+            TelemetryType::Request,
+        )
+        .expect("filter is valid");
+        let document = request(false, "0.00:00:01.500000", "/users");
+        assert_eq!(expected, filter.matches(&document));
+    }
+
+    #[test_case("Url", "Contains", "x", true ; "known field")]
+    #[test_case("Target", "Contains", "x", false ; "field of other telemetry type")]
+    #[test_case("Duration", "GreaterThan", "slow", false ; "non numeric comparand")]
+    #[test_case("*", "Equal", "x", false ; "any field equal")]
+    #[test_case("Name", "Matches", "x", false ; "unknown predicate")]
+    fn filter_validation(field_name: &str, predicate: &str, comparand: &str, valid: bool) {
+        let filter = Filter::new(
+            FilterInfo {
+                field_name: field_name.into(),
+                predicate: predicate.into(),
+                comparand: comparand.into(),
+            },
+            TelemetryType::Request,
+        );
+        assert_eq!(valid, filter.is_ok());
+    }
+
+    #[test]
+    fn derived_metrics() {
+        let mut configuration = configuration(
+            r#"{
+                "ETag": "etag",
+                "Metrics": [
+                    {
+                        "Id": "failed",
+                        "TelemetryType": "Request",
+                        "FilterGroups": [
+                            {"Filters": [{"FieldName": "Success", "Predicate": "Equal", "Comparand": "false"}]}
+                        ],
+                        "Projection": "Count()",
+                        "Aggregation": "Sum"
+                    },
+                    {
+                        "Id": "duration",
+                        "TelemetryType": "Request",
+                        "FilterGroups": [],
+                        "Projection": "Duration",
+                        "Aggregation": "Avg"
+                    },
+                    {
+                        "Id": "max-duration",
+                        "TelemetryType": "Request",
+                        "Projection": "Duration",
+                        "Aggregation": "Max"
+                    },
+                    {
+                        "Id": "invalid",
+                        "TelemetryType": "PerformanceCounter",
+                        "Projection": "Count()",
+                        "Aggregation": "Sum"
+                    }
+                ]
+            }"#,
+        );
+        configuration.record(&request(true, "0.00:00:00.100000", "/a"));
+        configuration.record(&request(false, "0.00:00:00.300000", "/a"));
+        let metrics: Vec<_> = configuration
+            .collect_and_reset()
+            .into_iter()
+            .map(|metric| (metric.name, metric.value))
+            .collect();
+        assert_eq!(
+            vec![
+                ("failed".into(), 1.),
+                ("duration".into(), 200.),
+                ("max-duration".into(), 300.)
+            ],
+            metrics
+        );
+
+        let metrics: Vec<_> = configuration
+            .collect_and_reset()
+            .into_iter()
+            .map(|metric| (metric.name, metric.value))
+            .collect();
+        assert_eq!(vec![("failed".into(), 0.)], metrics);
+    }
+
+    #[test]
+    fn document_streams() {
+        let configuration = configuration(
+            r#"{
+                "ETag": "etag",
+                "DocumentStreams": [
+                    {
+                        "Id": "all-requests",
+                        "DocumentFilterGroups": [{"TelemetryType": "Request", "Filters": {"Filters": []}}]
+                    },
+                    {
+                        "Id": "slow-requests",
+                        "DocumentFilterGroups": [
+                            {
+                                "TelemetryType": "Request",
+                                "Filters": {"Filters": [{"FieldName": "Duration", "Predicate": "GreaterThan", "Comparand": "200"}]}
+                            }
+                        ]
+                    },
+                    {
+                        "Id": "exceptions",
+                        "DocumentFilterGroups": [{"TelemetryType": "Exception", "Filters": {"Filters": []}}]
+                    }
+                ]
+            }"#,
+        );
+        assert!(configuration.has_document_streams());
+
+        assert_eq!(
+            vec!["all-requests".to_string()],
+            configuration.matching_document_streams(&request(true, "0.00:00:00.100000", "/a"))
+        );
+        assert_eq!(
+            vec!["all-requests".to_string(), "slow-requests".to_string()],
+            configuration.matching_document_streams(&request(true, "0.00:00:00.300000", "/a"))
+        );
+    }
+}
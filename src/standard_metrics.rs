@@ -0,0 +1,257 @@
+use crate::{
+    convert::time_to_string,
+    models::{
+        context_tag_keys::{self, Tags},
+        Data, DataPoint, DataPointType, Envelope, MetricData, Properties, RemoteDependencyData,
+        RequestData,
+    },
+    periodic::{PeriodicTask, Schedule},
+    tags::{get_tags_for_span, get_tags_from_attrs},
+    trace::get_duration,
+    Error,
+};
+use opentelemetry::{
+    trace::{SpanKind, TraceResult},
+    Context,
+};
+use opentelemetry_http::HttpClient;
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    runtime::RuntimeChannel,
+    trace::{Span, SpanProcessor},
+    Resource,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Interval in which pre-aggregated metrics are sent. Same as in the other Application Insights
+/// SDKs.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+const METRIC_ID_REQUEST_DURATION: &str = "requests/duration";
+const METRIC_ID_DEPENDENCY_DURATION: &str = "dependencies/duration";
+
+/// Property marking requests and dependencies which are already counted in standard metrics, so
+/// Application Insights doesn't count them again.
+pub(crate) const PROCESSED_BY_METRIC_EXTRACTORS: &str = "_MS.ProcessedByMetricExtractors";
+pub(crate) const PROCESSED_BY_METRIC_EXTRACTORS_VALUE: &str = "(Name:'X', Ver:'1.1')";
+
+type Dimensions = BTreeMap<&'static str, String>;
+
+/// Span processor which pre-aggregates the standard metrics of requests and dependencies, which
+/// back charts like "Server requests", "Server response time" and "Failed requests" in the
+/// Application Insights portal.
+pub(crate) struct StandardMetricsProcessor<R: RuntimeChannel> {
+    aggregator: Arc<Mutex<Aggregator>>,
+    task: PeriodicTask<R>,
+}
+
+impl<R: RuntimeChannel> std::fmt::Debug for StandardMetricsProcessor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StandardMetricsProcessor").finish()
+    }
+}
+
+impl<R: RuntimeChannel> StandardMetricsProcessor<R> {
+    pub(crate) fn new<C: HttpClient + 'static>(
+        client: Arc<C>,
+        endpoint: Arc<http::Uri>,
+        instrumentation_key: String,
+        resource: Resource,
+        sample_rate: f64,
+        runtime: R,
+    ) -> StandardMetricsProcessor<R> {
+        let tags = get_tags_from_attrs(resource.iter());
+        let aggregator = Arc::new(Mutex::new(Aggregator::new(sample_rate)));
+        let task_aggregator = aggregator.clone();
+        let schedule = Schedule {
+            interval: EXPORT_INTERVAL,
+            send_immediately: false,
+            send_on_shutdown: true,
+        };
+        let task = PeriodicTask::spawn(
+            client,
+            endpoint,
+            schedule,
+            Error::StandardMetricsShutdown,
+            move || {
+                task_aggregator
+                    .lock()
+                    .unwrap()
+                    .collect_and_reset(&instrumentation_key, &tags)
+            },
+            runtime,
+        );
+
+        StandardMetricsProcessor { aggregator, task }
+    }
+}
+
+impl<R: RuntimeChannel> SpanProcessor for StandardMetricsProcessor<R> {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.aggregator.lock().unwrap().record_span(&span);
+    }
+
+    /// Triggers sending the metrics aggregated so far. Doesn't wait until they are sent.
+    fn force_flush(&self) -> TraceResult<()> {
+        self.task.send_now().map_err(Error::StandardMetricsFlush)?;
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.task.shutdown()
+    }
+}
+
+#[derive(Debug)]
+struct Aggregate {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+#[derive(Debug)]
+struct Aggregator {
+    aggregates: BTreeMap<(&'static str, Dimensions), Aggregate>,
+    /// Number of spans each sampled span stands for.
+    weight: f64,
+}
+
+impl Aggregator {
+    /// Creates an aggregator for spans sampled at `sample_rate` percent, which scales counts and
+    /// sums to estimate the metrics of all spans.
+    fn new(sample_rate: f64) -> Self {
+        let weight = if sample_rate > 0.0 && sample_rate < 100.0 {
+            100.0 / sample_rate
+        } else {
+            1.0
+        };
+        Aggregator {
+            aggregates: BTreeMap::new(),
+            weight,
+        }
+    }
+
+    fn record_span(&mut self, span: &SpanData) {
+        let mut tags = get_tags_for_span(span, &[]);
+        let mut dimensions = Dimensions::new();
+        let metric_id = match span.span_kind {
+            SpanKind::Server | SpanKind::Consumer => {
+                let data = RequestData::from(span);
+                dimensions.insert("Request.Success", bool_to_string(data.success));
+                dimensions.insert("request/resultCode", data.response_code.as_ref().into());
+                METRIC_ID_REQUEST_DURATION
+            }
+            SpanKind::Client | SpanKind::Producer | SpanKind::Internal => {
+                let data = RemoteDependencyData::from(span);
+                dimensions.insert(
+                    "Dependency.Success",
+                    bool_to_string(data.success.unwrap_or(true)),
+                );
+                dimensions.insert(
+                    "Dependency.Type",
+                    data.type_
+                        .map_or_else(|| "Other".into(), |x| x.as_ref().into()),
+                );
+                dimensions.insert(
+                    "dependency/resultCode",
+                    data.result_code
+                        .map_or_else(String::new, |x| x.as_ref().into()),
+                );
+                dimensions.insert(
+                    "dependency/target",
+                    data.target.map_or_else(String::new, |x| x.as_ref().into()),
+                );
+                METRIC_ID_DEPENDENCY_DURATION
+            }
+        };
+        dimensions.insert(
+            "cloud/roleName",
+            tags.remove(context_tag_keys::CLOUD_ROLE)
+                .unwrap_or_default(),
+        );
+        dimensions.insert(
+            "cloud/roleInstance",
+            tags.remove(context_tag_keys::CLOUD_ROLE_INSTANCE)
+                .unwrap_or_default(),
+        );
+        dimensions.insert(
+            "operation/synthetic",
+            bool_to_string(
+                tags.remove(context_tag_keys::OPERATION_SYNTHETIC_SOURCE)
+                    .is_some(),
+            ),
+        );
+
+        let duration_ms = get_duration(span).as_secs_f64() * 1000.;
+        self.aggregates
+            .entry((metric_id, dimensions))
+            .and_modify(|aggregate| {
+                aggregate.count += 1;
+                aggregate.sum += duration_ms;
+                aggregate.min = aggregate.min.min(duration_ms);
+                aggregate.max = aggregate.max.max(duration_ms);
+            })
+            .or_insert(Aggregate {
+                count: 1,
+                sum: duration_ms,
+                min: duration_ms,
+                max: duration_ms,
+            });
+    }
+
+    fn collect_and_reset(&mut self, instrumentation_key: &str, tags: &Tags) -> Vec<Envelope> {
+        let time = time_to_string(SystemTime::now());
+        let weight = self.weight;
+        std::mem::take(&mut self.aggregates)
+            .into_iter()
+            .map(|((metric_id, dimensions), aggregate)| {
+                let properties: Properties = vec![
+                    ("_MS.MetricId", metric_id.to_string()),
+                    ("_MS.IsAutocollected", "True".into()),
+                ]
+                .into_iter()
+                .chain(dimensions)
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect();
+                Envelope {
+                    name: "Microsoft.ApplicationInsights.Metric",
+                    time: time.clone().into(),
+                    sample_rate: None,
+                    i_key: Some(instrumentation_key.to_string().into()),
+                    tags: Some(tags.clone()),
+                    data: Some(Data::Metric(MetricData {
+                        ver: 2,
+                        metrics: vec![DataPoint {
+                            ns: None,
+                            name: metric_id.into(),
+                            kind: Some(DataPointType::Aggregation {
+                                count: Some(
+                                    ((aggregate.count as f64 * weight).round() as u64)
+                                        .try_into()
+                                        .unwrap_or(i32::MAX),
+                                ),
+                                min: Some(aggregate.min),
+                                max: Some(aggregate.max),
+                                std_dev: None,
+                            }),
+                            value: aggregate.sum * weight,
+                        }],
+                        properties: Some(properties),
+                    })),
+                }
+            })
+            .collect()
+    }
+}
+
+fn bool_to_string(value: bool) -> String {
+    if value { "True" } else { "False" }.into()
+}
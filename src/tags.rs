@@ -1,23 +1,65 @@
-use crate::models::context_tag_keys::{self as tags, Tags, TAG_KEY_LOOKUP};
+use crate::{
+    models::context_tag_keys::{self as tags, ContextTagKey, Tags, TAG_KEY_LOOKUP},
+    trace::DEPRECATED_HTTP_CLIENT_IP,
+    Error,
+};
 #[cfg(feature = "metrics")]
 use opentelemetry::InstrumentationLibrary;
 use opentelemetry::{
-    trace::{SpanId, SpanKind},
+    global,
+    trace::{Event, SpanId, SpanKind, TraceError},
     Key, Value,
 };
 use opentelemetry_sdk::export::trace::SpanData;
 #[cfg(feature = "metrics")]
 use opentelemetry_sdk::{AttributeSet, Resource};
 use opentelemetry_semantic_conventions as semcov;
-use std::collections::HashMap;
+use std::{collections::HashMap, net::IpAddr};
+
+/// Substrings of user agents (lowercase) of known health probes and bots and the synthetic source
+/// they map to. Health probes come first, because some of them contain generic bot keywords.
+const SYNTHETIC_USER_AGENTS: &[(&str, &str)] = &[
+    ("alwayson", "AlwaysOn"),
+    ("kube-probe", "HealthProbe"),
+    ("elb-healthchecker", "HealthProbe"),
+    ("googlehc", "HealthProbe"),
+    ("azure traffic manager endpoint monitor", "HealthProbe"),
+    ("edge health probe", "HealthProbe"),
+    ("bot", "Bot"),
+    ("crawl", "Bot"),
+    ("spider", "Bot"),
+    ("slurp", "Bot"),
+];
+
+/// Span attributes, which the user configured to be mapped to context tags.
+pub(crate) type TagAttributes = Vec<(ContextTagKey, Key)>;
+
+/// Maps the attribute to the tag, or reports the tag if it's not a known context tag.
+pub(crate) fn push_tag_attribute(tag_attributes: &mut TagAttributes, tag: &str, attribute: Key) {
+    match TAG_KEY_LOOKUP.get(tag) {
+        Some(tag) => tag_attributes.push((tag.clone(), attribute)),
+        None => global::handle_error(TraceError::from(Error::UnknownContextTag(tag.into()))),
+    }
+}
 
-pub(crate) fn get_tags_for_span(span: &SpanData) -> Tags {
+pub(crate) fn get_tags_for_span(span: &SpanData, tag_attributes: &[(ContextTagKey, Key)]) -> Tags {
     let mut tags = get_tags_from_attrs(
         span.resource
             .iter()
             .chain(span.attributes.iter().map(|kv| (&kv.key, &kv.value))),
     );
 
+    let attrs_map: HashMap<&str, &Value> = span
+        .attributes
+        .iter()
+        .map(|kv| (kv.key.as_str(), &kv.value))
+        .collect();
+    for (tag, key) in tag_attributes {
+        if let Some(value) = attrs_map.get(key.as_str()) {
+            tags.insert(tag.clone(), value.as_str().into_owned());
+        }
+    }
+
     // Set the operation id and operation parent id.
     tags.insert(tags::OPERATION_ID, span.span_context.trace_id().to_string());
     if span.parent_span_id != SpanId::INVALID {
@@ -25,38 +67,83 @@ pub(crate) fn get_tags_for_span(span: &SpanData) -> Tags {
     }
 
     // Ensure the name of the operation is `METHOD /the/route/path`.
+    if let Some(operation_name) = get_operation_name(span) {
+        tags.insert(tags::OPERATION_NAME, operation_name);
+    }
+
     if span.span_kind == SpanKind::Server || span.span_kind == SpanKind::Consumer {
-        let mut method: Option<&Value> = None;
-        let mut route: Option<&Value> = None;
-        for kv in &span.attributes {
-            #[allow(deprecated)]
-            if kv.key.as_str() == semcov::trace::HTTP_REQUEST_METHOD
-                || kv.key.as_str() == semcov::trace::HTTP_METHOD
-            {
-                method = Some(&kv.value);
-            } else if kv.key.as_str() == semcov::trace::HTTP_ROUTE {
-                route = Some(&kv.value);
-            }
+        if let Some(client_ip) = attrs_map
+            .get(semcov::trace::CLIENT_ADDRESS)
+            .or_else(|| attrs_map.get(DEPRECATED_HTTP_CLIENT_IP))
+            .map(|v| v.as_str())
+            .filter(|v| v.parse::<IpAddr>().is_ok())
+        {
+            tags.insert(tags::LOCATION_IP, client_ip.into_owned());
         }
 
-        if let (Some(method), Some(route)) = (method, route) {
-            tags.insert(
-                tags::OPERATION_NAME,
-                format!("{} {}", method.as_str(), route.as_str()),
-            );
+        if let Some(synthetic_source) = attrs_map
+            .get(semcov::trace::USER_AGENT_ORIGINAL)
+            .and_then(|user_agent| get_synthetic_source(&user_agent.as_str()))
+        {
+            tags.insert(tags::OPERATION_SYNTHETIC_SOURCE, synthetic_source.into());
         }
     }
 
     tags
 }
 
-pub(crate) fn get_tags_for_event(span: &SpanData) -> Tags {
-    let mut tags = Tags::new();
-    tags.insert(tags::OPERATION_ID, span.span_context.trace_id().to_string());
+fn get_synthetic_source(user_agent: &str) -> Option<&'static str> {
+    let user_agent = user_agent.to_lowercase();
+    SYNTHETIC_USER_AGENTS
+        .iter()
+        .find(|(pattern, _)| user_agent.contains(pattern))
+        .map(|(_, synthetic_source)| *synthetic_source)
+}
+
+/// Name of the operation started by a request span: `METHOD /the/route/path`.
+pub(crate) fn get_operation_name(span: &SpanData) -> Option<String> {
+    if span.span_kind != SpanKind::Server && span.span_kind != SpanKind::Consumer {
+        return None;
+    }
+
+    let mut method: Option<&Value> = None;
+    let mut route: Option<&Value> = None;
+    for kv in &span.attributes {
+        #[allow(deprecated)]
+        if kv.key.as_str() == semcov::trace::HTTP_REQUEST_METHOD
+            || kv.key.as_str() == semcov::trace::HTTP_METHOD
+        {
+            method = Some(&kv.value);
+        } else if kv.key.as_str() == semcov::trace::HTTP_ROUTE {
+            route = Some(&kv.value);
+        }
+    }
+
+    if let (Some(method), Some(route)) = (method, route) {
+        Some(format!("{} {}", method.as_str(), route.as_str()))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn get_tags_for_event(
+    span: &SpanData,
+    event: &Event,
+    tag_attributes: &[(ContextTagKey, Key)],
+) -> Tags {
+    let mut tags = get_tags_for_span(span, tag_attributes);
     tags.insert(
         tags::OPERATION_PARENT_ID,
         span.span_context.span_id().to_string(),
     );
+
+    // Allow events to override tags of their span with attributes that start with `ai.`.
+    for kv in event.attributes.iter() {
+        if let Some(ctk) = TAG_KEY_LOOKUP.get(kv.key.as_str()) {
+            tags.insert(ctk.clone(), kv.value.to_string());
+        }
+    }
+
     tags
 }
 
@@ -142,3 +229,18 @@ where
 
     tags
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:72.0) Gecko/20100101 Firefox/72.0", None ; "browser")]
+    #[test_case("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", Some("Bot") ; "googlebot")]
+    #[test_case("kube-probe/1.27", Some("HealthProbe") ; "kubernetes")]
+    #[test_case("ELB-HealthChecker/2.0", Some("HealthProbe") ; "aws elb")]
+    #[test_case("AlwaysOn", Some("AlwaysOn") ; "azure always on")]
+    fn synthetic_source(user_agent: &str, expected: Option<&'static str>) {
+        assert_eq!(expected, get_synthetic_source(user_agent));
+    }
+}
@@ -1,12 +1,16 @@
+#[cfg(feature = "metrics")]
+use crate::standard_metrics::{
+    PROCESSED_BY_METRIC_EXTRACTORS, PROCESSED_BY_METRIC_EXTRACTORS_VALUE,
+};
 use crate::{
     convert::{
         attrs_to_properties, duration_to_string, status_to_result_code, time_to_string,
         value_to_severity_level,
     },
     models::{
-        context_tag_keys::attrs::CUSTOM_EVENT_NAME, Data, Envelope, EventData, ExceptionData,
-        ExceptionDetails, LimitedLenString, MessageData, Properties, RemoteDependencyData,
-        RequestData,
+        context_tag_keys::{self, attrs::CUSTOM_EVENT_NAME, Tags},
+        finish_truncation, Data, Envelope, EventData, ExceptionData, ExceptionDetails,
+        LimitedLenString, MessageData, Properties, RemoteDependencyData, RequestData,
     },
     tags::{get_tags_for_event, get_tags_for_span},
     Exporter,
@@ -40,7 +44,7 @@ const DEPRECATED_NET_PEER_IP: &str = "net.peer.ip";
 ///
 /// Replaced in https://github.com/open-telemetry/opentelemetry-specification/pull/3402 with
 /// `client.address`.
-const DEPRECATED_HTTP_CLIENT_IP: &str = "http.client_ip";
+pub(crate) const DEPRECATED_HTTP_CLIENT_IP: &str = "http.client_ip";
 
 pub(crate) const EVENT_NAME_CUSTOM: &str = "ai.custom";
 pub(crate) const EVENT_NAME_EXCEPTION: &str = "exception";
@@ -48,11 +52,29 @@ pub(crate) const EVENT_NAME_EXCEPTION: &str = "exception";
 impl<C> Exporter<C> {
     fn create_envelopes_for_span(&self, span: SpanData) -> Vec<Envelope> {
         let mut result = Vec::with_capacity(1 + span.events.len());
+        let operation_name = self
+            .operation_names
+            .as_ref()
+            .and_then(|operation_names| operation_names.take(span.span_context.span_id()));
+        // The operation name of the span itself wins over the one propagated from its request.
+        let set_operation_name = |mut tags: Tags| {
+            if let Some(operation_name) = &operation_name {
+                if tags.get(&context_tag_keys::OPERATION_NAME).is_none() {
+                    tags.insert(context_tag_keys::OPERATION_NAME, operation_name.to_string());
+                }
+            }
+            tags
+        };
 
         let (data, tags, name) = match span.span_kind {
             SpanKind::Server | SpanKind::Consumer => {
-                let data: RequestData = (&span).into();
-                let tags = get_tags_for_span(&span);
+                #[allow(unused_mut)]
+                let mut data: RequestData = (&span).into();
+                #[cfg(feature = "metrics")]
+                if self.standard_metrics {
+                    mark_processed_by_metric_extractors(&mut data.properties);
+                }
+                let tags = get_tags_for_span(&span, &self.tag_attributes);
                 (
                     Data::Request(data),
                     tags,
@@ -60,8 +82,13 @@ impl<C> Exporter<C> {
                 )
             }
             SpanKind::Client | SpanKind::Producer | SpanKind::Internal => {
-                let data: RemoteDependencyData = (&span).into();
-                let tags = get_tags_for_span(&span);
+                #[allow(unused_mut)]
+                let mut data: RemoteDependencyData = (&span).into();
+                #[cfg(feature = "metrics")]
+                if self.standard_metrics {
+                    mark_processed_by_metric_extractors(&mut data.properties);
+                }
+                let tags = get_tags_for_span(&span, &self.tag_attributes);
                 (
                     Data::RemoteDependency(data),
                     tags,
@@ -74,7 +101,7 @@ impl<C> Exporter<C> {
             time: time_to_string(span.start_time).into(),
             sample_rate: Some(self.sample_rate),
             i_key: Some(self.instrumentation_key.clone().into()),
-            tags: Some(tags),
+            tags: Some(set_operation_name(tags)),
             data: Some(data),
         });
 
@@ -98,7 +125,11 @@ impl<C> Exporter<C> {
                 time: time_to_string(event.timestamp).into(),
                 sample_rate: Some(self.sample_rate),
                 i_key: Some(self.instrumentation_key.clone().into()),
-                tags: Some(get_tags_for_event(&span)),
+                tags: Some(set_operation_name(get_tags_for_event(
+                    &span,
+                    event,
+                    &self.tag_attributes,
+                ))),
                 data: Some(data),
             });
         }
@@ -115,10 +146,15 @@ where
     fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
         let client = Arc::clone(&self.client);
         let endpoint = Arc::clone(&self.endpoint);
-        let envelopes: Vec<_> = batch
+        let mut envelopes: Vec<_> = batch
             .into_iter()
             .flat_map(|span| self.create_envelopes_for_span(span))
             .collect();
+        finish_truncation(
+            &mut envelopes,
+            self.truncation_marker.as_deref(),
+            &self.diagnostics,
+        );
 
         Box::pin(async move {
             crate::uploader::send(client.as_ref(), endpoint.as_ref(), envelopes).await?;
@@ -127,6 +163,14 @@ where
     }
 }
 
+#[cfg(feature = "metrics")]
+fn mark_processed_by_metric_extractors(properties: &mut Option<Properties>) {
+    properties.get_or_insert_with(Properties::new).insert(
+        PROCESSED_BY_METRIC_EXTRACTORS.into(),
+        PROCESSED_BY_METRIC_EXTRACTORS_VALUE.into(),
+    );
+}
+
 fn get_url_path_and_query<'v>(attrs: &HashMap<&str, &'v Value>) -> Option<Cow<'v, str>> {
     if let Some(path) = attrs.get(semcov::trace::URL_PATH) {
         if let Some(query) = attrs.get(semcov::trace::URL_QUERY) {
@@ -367,26 +411,45 @@ impl From<&SpanData> for RemoteDependencyData {
             data.target = Some(db_name.into());
         }
 
-        if span.span_kind == SpanKind::Internal {
-            data.type_ = Some("InProc".into());
-        } else if let Some(&db_system) = attrs.get(semcov::trace::DB_SYSTEM) {
-            data.type_ = Some(db_system.into());
-        } else if let Some(&messaging_system) = attrs.get(semcov::trace::MESSAGING_SYSTEM) {
-            data.type_ = Some(messaging_system.into());
-        } else if let Some(&rpc_system) = attrs.get(semcov::trace::RPC_SYSTEM) {
-            data.type_ = Some(rpc_system.into());
-        } else if let Some(ref properties) = data.properties {
-            if properties.keys().any(|x| x.as_ref().starts_with("http.")) {
-                data.type_ = Some("HTTP".into());
-            } else if properties.keys().any(|x| x.as_ref().starts_with("db.")) {
-                data.type_ = Some("DB".into());
-            }
-        }
+        data.type_ = dependency_type(span).map(|type_| type_.as_ref().into());
 
         data
     }
 }
 
+/// Type of a remote dependency, e.g. the database, messaging or RPC system.
+fn dependency_type(span: &SpanData) -> Option<Cow<'_, str>> {
+    if span.span_kind == SpanKind::Internal {
+        return Some("InProc".into());
+    }
+    let attr = |key: &str| {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.as_str())
+    };
+    if let Some(system) = attr(semcov::trace::DB_SYSTEM)
+        .or_else(|| attr(semcov::trace::MESSAGING_SYSTEM))
+        .or_else(|| attr(semcov::trace::RPC_SYSTEM))
+    {
+        return Some(system);
+    }
+    let has_key_with_prefix = |prefix: &str| {
+        span.attributes
+            .iter()
+            .map(|kv| kv.key.as_str())
+            .chain(span.resource.iter().map(|(k, _)| k.as_str()))
+            .any(|key| key.starts_with(prefix))
+    };
+    if has_key_with_prefix("http.") {
+        Some("HTTP".into())
+    } else if has_key_with_prefix("db.") {
+        Some("DB".into())
+    } else {
+        None
+    }
+}
+
 impl From<&Event> for ExceptionData {
     fn from(event: &Event) -> ExceptionData {
         let mut attrs: HashMap<&str, &Value> = event
@@ -1,9 +1,10 @@
 use crate::{
-    models::QuickPulseEnvelope,
+    models::{CollectionConfigurationInfo, QuickPulseEnvelope},
     uploader::{append_path, serialize_request_body},
     Error, HttpClient,
 };
 use http::{HeaderName, Request, Uri};
+use opentelemetry::trace::TraceError;
 use std::{
     convert::TryFrom,
     time::{Duration, SystemTime},
@@ -29,6 +30,8 @@ const QPS_REDIRECT: HeaderName = HeaderName::from_static("x-ms-qps-service-endpo
 #[allow(clippy::declare_interior_mutable_const)]
 const QPS_INTERVAL_HINT: HeaderName =
     HeaderName::from_static("x-ms-qps-service-endpoint-interval-hint");
+#[allow(clippy::declare_interior_mutable_const)]
+const QPS_CONFIGURATION_ETAG: HeaderName = HeaderName::from_static("x-ms-qps-configuration-etag");
 
 pub(crate) enum PostOrPing {
     Post,
@@ -48,12 +51,19 @@ pub(crate) struct QuickPulseResponse {
     pub(crate) should_post: bool,
     pub(crate) redirected_host: Option<http::Uri>,
     pub(crate) polling_interval_hint: Option<std::time::Duration>,
+    /// ETag of the collection configuration, if it differs from the one sent with the request.
+    /// Set even if the configuration can't be parsed, so the error is only reported once.
+    pub(crate) configuration_etag: Option<String>,
+    /// New collection configuration, if its ETag differs from the one sent with the request.
+    pub(crate) configuration: Option<CollectionConfigurationInfo>,
 }
 
 pub(crate) async fn send(
     client: &dyn HttpClient,
     endpoint: &Uri,
     instrumentation_key: &str,
+    configuration_etag: &str,
+    host_info: bool,
     post_or_ping: PostOrPing,
     envelope: QuickPulseEnvelope,
 ) -> Result<QuickPulseResponse, Error> {
@@ -76,13 +86,18 @@ pub(crate) async fn send(
         )
         .header(http::header::CONTENT_TYPE, "application/json")
         .header(http::header::CONTENT_ENCODING, "gzip");
+    if !configuration_etag.is_empty() {
+        request_builder = request_builder.header(QPS_CONFIGURATION_ETAG, configuration_etag);
+    }
     if matches!(post_or_ping, PostOrPing::Ping) {
-        request_builder = request_builder
-            .header(QPS_STREAM_ID, envelope.stream_id)
-            .header(QPS_MACHINE_NAME, envelope.machine_name)
-            .header(QPS_INSTANCE_NAME, envelope.instance)
-            .header(QPS_INVARIANT_VERSION, envelope.invariant_version);
-        if let Some(role_name) = envelope.role_name {
+        request_builder = request_builder.header(QPS_STREAM_ID, envelope.stream_id);
+        if host_info {
+            request_builder = request_builder
+                .header(QPS_MACHINE_NAME, envelope.machine_name)
+                .header(QPS_INSTANCE_NAME, envelope.instance);
+        }
+        request_builder = request_builder.header(QPS_INVARIANT_VERSION, envelope.invariant_version);
+        if let (true, Some(role_name)) = (host_info, envelope.role_name) {
             request_builder = request_builder.header(QPS_ROLE_NAME, role_name);
         }
     }
@@ -114,10 +129,31 @@ pub(crate) async fn send(
             .and_then(|v| v.to_str().ok())
             .and_then(|v| v.parse::<u64>().ok())
             .map(Duration::from_millis);
+        let new_configuration_etag = response
+            .headers()
+            .get(QPS_CONFIGURATION_ETAG)
+            .and_then(|v| v.to_str().ok())
+            .filter(|etag| *etag != configuration_etag)
+            .map(String::from);
+        let configuration = new_configuration_etag.as_ref().and_then(|etag| {
+            serde_json::from_slice(response.body())
+                .map(|configuration| CollectionConfigurationInfo {
+                    etag: etag.clone(),
+                    ..configuration
+                })
+                .map_err(|err| {
+                    opentelemetry::global::handle_error(TraceError::from(
+                        Error::UploadDeserializeResponse(err),
+                    ))
+                })
+                .ok()
+        });
         Ok(QuickPulseResponse {
             should_post,
             redirected_host,
             polling_interval_hint,
+            configuration_etag: new_configuration_etag,
+            configuration,
         })
     } else {
         Err(Error::Upload(format!(
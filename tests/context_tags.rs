@@ -0,0 +1,35 @@
+//! Tests for reporting unknown context tags. They set the global error handler, so they run in
+//! their own process.
+
+use opentelemetry_application_insights::{attrs as ai, Exporter};
+use std::sync::{Arc, Mutex};
+
+// Fake instrumentation key (this is a random uuid)
+const CONNECTION_STRING: &str = "InstrumentationKey=0fdcec70-0ce5-4085-89d9-9ae8ead9af66";
+
+#[test]
+fn report_unknown_context_tags() {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let handler_errors = errors.clone();
+    opentelemetry::global::set_error_handler(move |err| {
+        handler_errors.lock().unwrap().push(err.to_string());
+    })
+    .expect("error handler can be set");
+
+    let _pipeline =
+        opentelemetry_application_insights::new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_context_tag_attribute(ai::SESSION_ID, "session.id")
+            .with_context_tag_attribute("ai.sesion.id", "session.id");
+    let exporter = Exporter::new_from_connection_string(CONNECTION_STRING, ())
+        .expect("connection string is valid")
+        .with_context_tag_attribute(ai::USER_ID, "user.id")
+        .with_context_tag_attribute("user.id", "user.id");
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(2, errors.len(), "{:?}", errors);
+    assert!(errors[0].contains(r#"unknown context tag "ai.sesion.id""#));
+    assert!(errors[1].contains(r#"unknown context tag "user.id""#));
+    let exporter = format!("{:?}", exporter);
+    assert!(exporter.contains("ai.user.id"), "{}", exporter);
+}
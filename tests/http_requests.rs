@@ -14,7 +14,9 @@ use opentelemetry::{
     },
     Context, KeyValue,
 };
-use opentelemetry_application_insights::{attrs as ai, new_pipeline_from_connection_string};
+use opentelemetry_application_insights::{
+    attrs as ai, new_pipeline_from_connection_string, LiveMetricsConfig, LiveMetricsState,
+};
 use opentelemetry_sdk::Resource;
 use opentelemetry_semantic_conventions as semcov;
 use recording_client::record;
@@ -49,6 +51,8 @@ fn traces_simple() {
                     KeyValue::new(semcov::resource::SERVICE_NAME, "server"),
                 ])),
             )
+            .with_operation_name_propagation(true)
+            .with_context_tag_attribute(ai::SESSION_ID, "session.id")
             .build_simple();
         let server_tracer = server_provider.tracer("test");
 
@@ -93,6 +97,7 @@ fn traces_simple() {
                     KeyValue::new(semcov::trace::CLIENT_SOCKET_ADDRESS, "10.1.2.2"),
                     KeyValue::new(semcov::trace::USER_AGENT_ORIGINAL, "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:72.0) Gecko/20100101 Firefox/72.0"),
                     KeyValue::new(semcov::trace::ENDUSER_ID,"marry"),
+                    KeyValue::new("session.id", "42"),
                 ]);
             let span = server_tracer.build_with_context(builder, &cx);
             {
@@ -105,6 +110,7 @@ fn traces_simple() {
                             // Emulate tracing level
                             // https://docs.rs/tracing-core/0.1.30/src/tracing_core/metadata.rs.html#531
                             KeyValue::new("level", "WARN"),
+                            KeyValue::new(ai::DEVICE_LOCALE, "en-GB"),
                         ],
                     );
                     span.add_event(
@@ -117,6 +123,9 @@ fn traces_simple() {
                     let error: Box<dyn std::error::Error> = "An error".into();
                     span.record_error(error.as_ref());
                 });
+
+                // The server does some work within the request
+                server_tracer.in_span("internal work", |_cx| {});
             }
 
             // Force the server span to be sent before the client span. Without this on Jan's PC
@@ -189,6 +198,7 @@ async fn live_metrics() {
                 .start(&tracer);
             let error: Box<dyn std::error::Error> = "An error".into();
             span.record_error(error.as_ref());
+            span.add_event("A message", vec![KeyValue::new("level", "WARN")]);
         }
 
         // Wait for two pong requests.
@@ -198,6 +208,418 @@ async fn live_metrics() {
     insta::assert_snapshot!(live_metrics);
 }
 
+#[test]
+fn live_metrics_simple() {
+    let requests = record(NoTick, |client| {
+        let pipeline = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_live_metrics(true)
+            .with_live_metrics_config(
+                LiveMetricsConfig::new()
+                    .with_ping_interval(Duration::from_secs(1))
+                    .with_host_info(false),
+            );
+        let status = pipeline.live_metrics_status();
+        assert_eq!(LiveMetricsState::Idle, status.state());
+        let tracer_provider = pipeline.build_simple();
+        let tracer = tracer_provider.tracer("test");
+        assert_eq!(LiveMetricsState::Pinging, status.state());
+
+        // Wait for one ping request so we start to collect metrics.
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(LiveMetricsState::Posting, status.state());
+
+        {
+            let _span = tracer
+                .span_builder("live-metrics")
+                .with_kind(SpanKind::Server)
+                .with_status(Status::error(""))
+                .start(&tracer);
+        }
+
+        // Wait for the metrics to be posted.
+        std::thread::sleep(Duration::from_secs(2));
+        drop(tracer_provider);
+        assert_eq!(LiveMetricsState::Idle, status.state());
+    });
+    let live_metrics = requests_to_string(requests);
+    assert!(live_metrics.contains("/QuickPulseService.svc/ping"));
+    assert!(live_metrics.contains("/QuickPulseService.svc/post"));
+    assert!(!live_metrics.contains("x-ms-qps-machine-name"));
+    assert!(live_metrics.contains(
+        r#""Name": "\\ApplicationInsights\\Requests Failed/Sec",
+        "Value": 1.0"#
+    ));
+}
+
+#[test]
+fn live_metrics_counters() {
+    let requests = record(NoTick, |client| {
+        let pipeline = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_live_metrics(true)
+            .with_live_metrics_config(
+                LiveMetricsConfig::new().with_ping_interval(Duration::from_secs(1)),
+            )
+            .with_live_metrics_counter("Queue depth", || 42.0)
+            .with_live_metrics_counter("Invalid", || f64::NAN)
+            .with_live_metrics_counter("Panics", || panic!("counter failed"))
+            .with_live_metrics_counter("\\ApplicationInsights\\Requests/Sec", || 1000.0);
+        let status = pipeline.live_metrics_status();
+        let tracer_provider = pipeline.build_simple();
+
+        // Wait for one ping request so we start to collect metrics and for some posts.
+        std::thread::sleep(Duration::from_secs(4));
+        assert_eq!(LiveMetricsState::Posting, status.state());
+        drop(tracer_provider);
+    });
+    let live_metrics = requests_to_string(requests);
+    assert!(live_metrics.contains(
+        r#""Name": "Queue depth",
+        "Value": 42.0"#
+    ));
+    assert!(!live_metrics.contains(r#""Name": "Invalid""#));
+    assert!(!live_metrics.contains(r#""Name": "Panics""#));
+    assert!(!live_metrics.contains(r#""Value": 1000.0"#));
+}
+
+#[test]
+fn live_metrics_final_post() {
+    let requests = record(NoTick, |client| {
+        let pipeline = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_live_metrics(true)
+            .with_live_metrics_config(
+                LiveMetricsConfig::new()
+                    .with_ping_interval(Duration::from_secs(1))
+                    // Only the final post on shutdown sends metrics.
+                    .with_post_interval(Duration::from_secs(60))
+                    .with_final_post(true),
+            );
+        let status = pipeline.live_metrics_status();
+        let tracer_provider = pipeline.build_simple();
+        let tracer = tracer_provider.tracer("test");
+
+        // Wait for one ping request so we start to collect metrics.
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(LiveMetricsState::Posting, status.state());
+
+        {
+            let _span = tracer
+                .span_builder("live-metrics")
+                .with_kind(SpanKind::Server)
+                .with_status(Status::error(""))
+                .start(&tracer);
+        }
+
+        // Rates are only sent for at least one second of collection.
+        std::thread::sleep(Duration::from_secs(1));
+        drop(tracer_provider);
+        assert_eq!(LiveMetricsState::Idle, status.state());
+    });
+    let live_metrics = requests_to_string(requests);
+    assert_eq!(
+        1,
+        live_metrics.matches("/QuickPulseService.svc/post").count()
+    );
+    assert!(live_metrics.contains(r#""Name": "\\ApplicationInsights\\Requests Failed/Sec""#));
+}
+
+#[cfg(feature = "metrics")]
+#[async_std::test]
+async fn live_metrics_from_logs_and_metrics() {
+    use opentelemetry::{
+        logs::{LogRecord, Logger as _, LoggerProvider as _, Severity},
+        metrics::MeterProvider as _,
+    };
+
+    let requests = record(AsyncStdTick, |client| {
+        let pipeline = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_live_metrics(true)
+            .with_live_metrics_instruments(["jobs"]);
+        let logger_provider = opentelemetry_sdk::logs::LoggerProvider::builder()
+            .with_log_processor(pipeline.live_metrics_log_processor())
+            .build();
+        let (tracer_provider, meter_provider) =
+            pipeline.build_batch_with_meter_provider(opentelemetry_sdk::runtime::AsyncStd);
+        let logger = logger_provider.logger("test");
+        let jobs = meter_provider.meter("test").u64_counter("jobs").init();
+        let ignored = meter_provider.meter("test").u64_counter("ignored").init();
+
+        // Wait for one ping request so we start to collect metrics.
+        std::thread::sleep(Duration::from_secs(6));
+
+        logger.emit(
+            LogRecord::builder()
+                .with_body("A log message")
+                .with_severity_number(Severity::Warn)
+                .build(),
+        );
+        logger.emit(
+            LogRecord::builder()
+                .with_body("An exception")
+                .with_severity_number(Severity::Error)
+                .with_attribute(semcov::trace::EXCEPTION_MESSAGE, "An error")
+                .build(),
+        );
+        jobs.add(3, &[]);
+        ignored.add(1, &[]);
+
+        // Wait for the metrics to be collected and posted.
+        std::thread::sleep(Duration::from_secs(3));
+        meter_provider.shutdown().expect("shutdown succeeds");
+        drop(tracer_provider);
+    });
+    let live_metrics = requests_to_string(requests);
+    assert!(live_metrics.contains(r#""Message": "A log message""#));
+    assert!(live_metrics.contains(r#""SeverityLevel": "Warning""#));
+    assert!(live_metrics.contains(r#""ExceptionMessage": "An error""#));
+    assert!(live_metrics.contains(
+        r#""Name": "\\ApplicationInsights\\Exceptions/Sec",
+        "Value": 1.0"#
+    ));
+    assert!(live_metrics.contains(
+        r#""Name": "jobs",
+        "Value": 3.0"#
+    ));
+    assert!(!live_metrics.contains(r#""Name": "ignored""#));
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn standard_metrics() {
+    use std::time::SystemTime;
+
+    let requests = record(TokioTick, |client| {
+        let tracer_provider = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_trace_config(
+                opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new(semcov::resource::SERVICE_NAME, "server"),
+                    KeyValue::new(semcov::resource::SERVICE_INSTANCE_ID, "instance"),
+                ])),
+            )
+            .with_standard_metrics(true)
+            .build_batch(opentelemetry_sdk::runtime::TokioCurrentThread);
+        let tracer = tracer_provider.tracer("test");
+
+        let start_time = SystemTime::now();
+        for (kind, status, duration_ms) in [
+            (SpanKind::Server, Status::Unset, 10),
+            (SpanKind::Server, Status::Unset, 30),
+            (SpanKind::Server, Status::error(""), 20),
+            (SpanKind::Client, Status::Ok, 5),
+        ] {
+            let mut span = tracer
+                .span_builder("standard-metrics")
+                .with_kind(kind)
+                .with_status(status)
+                .with_start_time(start_time)
+                .with_attributes(vec![
+                    KeyValue::new(semcov::trace::HTTP_REQUEST_METHOD, "GET"),
+                    KeyValue::new(semcov::trace::HTTP_RESPONSE_STATUS_CODE, 200),
+                ])
+                .start(&tracer);
+            span.end_with_timestamp(start_time + Duration::from_millis(duration_ms));
+        }
+
+        // Export spans first, then standard metrics.
+        tracer_provider.force_flush();
+    });
+    let standard_metrics = requests_to_string(requests);
+    insta::assert_snapshot!(standard_metrics);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn standard_metrics_sampled() {
+    use std::time::SystemTime;
+
+    let requests = record(TokioTick, |client| {
+        let tracer_provider = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_trace_config(
+                opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new(semcov::resource::SERVICE_NAME, "server"),
+                ])),
+            )
+            .with_sample_rate(0.5)
+            .with_standard_metrics(true)
+            .build_batch(opentelemetry_sdk::runtime::TokioCurrentThread);
+        let tracer = tracer_provider.tracer("test");
+
+        // Each sampled request stands for two requests.
+        let start_time = SystemTime::now();
+        for duration_ms in [10, 30] {
+            let mut span = tracer
+                .span_builder("standard-metrics")
+                .with_kind(SpanKind::Server)
+                .with_start_time(start_time)
+                .start(&tracer);
+            span.end_with_timestamp(start_time + Duration::from_millis(duration_ms));
+        }
+
+        tracer_provider.force_flush();
+    });
+    let standard_metrics_sampled = requests_to_string(requests);
+    insta::assert_snapshot!(standard_metrics_sampled);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn metrics_pipeline() {
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_sdk::metrics::{new_view, Instrument, Stream};
+
+    let requests = record(TokioTick, |client| {
+        let meter_provider = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_trace_config(
+                opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new(semcov::resource::SERVICE_NAME, "server"),
+                ])),
+            )
+            .with_metrics_view(
+                new_view(
+                    Instrument::new().name("requests"),
+                    Stream::new().name("http.server.requests"),
+                )
+                .expect("view is valid"),
+            )
+            .with_metrics_namespace("test", "pipeline")
+            .with_metrics_dropped_dimensions(["user.id"])
+            .with_metrics_histogram_percentiles([50.])
+            .build_meter_provider(opentelemetry_sdk::runtime::TokioCurrentThread);
+        let meter = meter_provider.meter("test");
+
+        let counter = meter.u64_counter("requests").init();
+        counter.add(
+            2,
+            &[
+                KeyValue::new("route", "/hello"),
+                KeyValue::new("user.id", "42"),
+            ],
+        );
+        let histogram = meter.f64_histogram("duration").init();
+        histogram.record(8.0, &[]);
+
+        meter_provider.shutdown().expect("shutdown succeeds");
+    });
+    let metrics_pipeline = requests_to_string(requests);
+    insta::assert_snapshot!(metrics_pipeline);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn metrics_exponential_histogram() {
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_application_insights::Exporter;
+    use opentelemetry_sdk::metrics::{
+        new_view, Aggregation, Instrument, PeriodicReader, SdkMeterProvider, Stream,
+    };
+
+    let requests = record(TokioTick, |client| {
+        let exporter = Exporter::new_from_connection_string(CONNECTION_STRING, client)
+            .expect("connection string is valid");
+        let reader =
+            PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+                .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_view(
+                new_view(
+                    Instrument::new().name("latency"),
+                    Stream::new().aggregation(Aggregation::Base2ExponentialHistogram {
+                        max_size: 160,
+                        max_scale: 0,
+                        record_min_max: true,
+                    }),
+                )
+                .expect("view is valid"),
+            )
+            .build();
+        let meter = meter_provider.meter("test");
+
+        let histogram = meter.f64_histogram("latency").init();
+        histogram.record(1.5, &[KeyValue::new("route", "/hello")]);
+        histogram.record(3.0, &[KeyValue::new("route", "/hello")]);
+
+        meter_provider.shutdown().expect("shutdown succeeds");
+    });
+    let metrics_exponential_histogram = requests_to_string(requests);
+    insta::assert_snapshot!(metrics_exponential_histogram);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn metrics_namespace() {
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_application_insights::Exporter;
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+
+    let requests = record(TokioTick, |client| {
+        let exporter = Exporter::new_from_connection_string(CONNECTION_STRING, client)
+            .expect("connection string is valid")
+            .with_metric_namespace("test", "test.namespace");
+        let reader =
+            PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+                .build();
+        let meter_provider = SdkMeterProvider::builder().with_reader(reader).build();
+
+        let meter = meter_provider.meter("test");
+
+        // Namespace mapped from the scope name
+        let counter = meter.u64_counter("requests").init();
+        counter.add(1, &[]);
+        // Namespace set with an attribute
+        let counter = meter.u64_counter("jobs").init();
+        counter.add(
+            1,
+            &[KeyValue::new("ai.metric.namespace", "custom.namespace")],
+        );
+
+        meter_provider.shutdown().expect("shutdown succeeds");
+    });
+    let metrics_namespace = requests_to_string(requests);
+    insta::assert_snapshot!(metrics_namespace);
+}
+
+#[cfg(feature = "heartbeat")]
+#[tokio::test]
+async fn heartbeat() {
+    let requests = record(TokioTick, |client| {
+        let tracer_provider = new_pipeline_from_connection_string(CONNECTION_STRING)
+            .expect("connection string is valid")
+            .with_client(client)
+            .with_trace_config(
+                opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new(semcov::resource::SERVICE_NAME, "server"),
+                    KeyValue::new(semcov::resource::OS_TYPE, "linux"),
+                ])),
+            )
+            .with_heartbeat(true)
+            .with_heartbeat_property("deployment", "blue")
+            .build_batch(opentelemetry_sdk::runtime::TokioCurrentThread);
+
+        // Wait for the first heartbeat, which is sent right away.
+        std::thread::sleep(Duration::from_secs(1));
+        drop(tracer_provider);
+    });
+    let heartbeat = requests_to_string(requests);
+    assert!(heartbeat.contains(r#""name": "HeartbeatState""#));
+    assert!(heartbeat.contains(r#""deployment": "blue""#));
+    assert!(heartbeat.contains(r#""osType": "linux""#));
+    assert!(heartbeat.contains(r#""ai.cloud.role": "server""#));
+}
+
 mod recording_client {
     use super::tick::Tick;
     use async_trait::async_trait;
@@ -349,9 +771,9 @@ mod format {
         let res = vec![
             Regex::new(r#""(?P<field>time)": "\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}.\d{3}Z""#)
                 .unwrap(),
-            Regex::new(r#""(?P<field>duration)": "\d+\.\d{2}:\d{2}:\d{2}\.\d{6}""#).unwrap(),
+            Regex::new(r#""(?P<field>duration|Duration)": "\d+\.\d{2}:\d{2}:\d{2}\.\d{6}""#).unwrap(),
             Regex::new(r#""(?P<field>id|ai\.operation\.parentId)": "[a-z0-9]{16}""#).unwrap(),
-            Regex::new(r#""(?P<field>ai\.operation\.id)": "[a-z0-9]{32}""#).unwrap(),
+            Regex::new(r#""(?P<field>ai\.operation\.id|OperationId)": "[a-z0-9]{32}""#).unwrap(),
             Regex::new(r#""(?P<field>StreamId)": "[a-z0-9]{32}""#).unwrap(),
             Regex::new(r#""(?P<field>Timestamp)": "/Date\(\d+\)/""#).unwrap(),
             Regex::new(
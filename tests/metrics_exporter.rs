@@ -0,0 +1,140 @@
+//! Tests for flushing and shutting down the metrics exporter while exports are in flight
+#![cfg(feature = "metrics")]
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Request, Response};
+use opentelemetry::{InstrumentationLibrary, KeyValue};
+use opentelemetry_application_insights::Exporter;
+use opentelemetry_http::{HttpClient, HttpError};
+use opentelemetry_sdk::{
+    metrics::{
+        data::{DataPoint, Gauge, Metric, ResourceMetrics, ScopeMetrics},
+        exporter::PushMetricsExporter,
+    },
+    Resource,
+};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+// Fake instrumentation key (this is a random uuid)
+const CONNECTION_STRING: &str = "InstrumentationKey=0fdcec70-0ce5-4085-89d9-9ae8ead9af66";
+
+const UPLOAD_DELAY: Duration = Duration::from_millis(500);
+
+#[test]
+fn shutdown_waits_for_exports_in_flight() {
+    let client = SlowRecordingClient::default();
+    let exporter = Arc::new(
+        Exporter::new_from_connection_string(CONNECTION_STRING, client.clone())
+            .expect("connection string is valid"),
+    );
+
+    let export = spawn_export(exporter.clone());
+    thread::sleep(UPLOAD_DELAY / 5);
+    assert_eq!(0, client.request_count());
+
+    exporter.shutdown().expect("shutdown succeeds");
+    assert_eq!(1, client.request_count());
+    export
+        .join()
+        .expect("export thread succeeds")
+        .expect("export succeeds");
+
+    // Exports after shutdown are rejected.
+    assert!(block_on(exporter.export(&mut resource_metrics())).is_err());
+    assert_eq!(1, client.request_count());
+}
+
+#[test]
+fn force_flush_waits_for_exports_in_flight() {
+    let client = SlowRecordingClient::default();
+    let exporter = Arc::new(
+        Exporter::new_from_connection_string(CONNECTION_STRING, client.clone())
+            .expect("connection string is valid"),
+    );
+
+    let export = spawn_export(exporter.clone());
+    thread::sleep(UPLOAD_DELAY / 5);
+    assert_eq!(0, client.request_count());
+
+    block_on(exporter.force_flush()).expect("force flush succeeds");
+    assert_eq!(1, client.request_count());
+    export
+        .join()
+        .expect("export thread succeeds")
+        .expect("export succeeds");
+
+    // Exports after a flush still work.
+    block_on(exporter.export(&mut resource_metrics())).expect("export succeeds");
+    assert_eq!(2, client.request_count());
+}
+
+fn spawn_export(
+    exporter: Arc<Exporter<SlowRecordingClient>>,
+) -> thread::JoinHandle<opentelemetry::metrics::Result<()>> {
+    thread::spawn(move || block_on(exporter.export(&mut resource_metrics())))
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("runtime builds")
+        .block_on(future)
+}
+
+fn resource_metrics() -> ResourceMetrics {
+    ResourceMetrics {
+        resource: Resource::empty(),
+        scope_metrics: vec![ScopeMetrics {
+            scope: InstrumentationLibrary::new("test", None::<&str>, None::<&str>, None),
+            metrics: vec![Metric {
+                name: "queue.length".into(),
+                description: "".into(),
+                unit: Default::default(),
+                data: Box::new(Gauge {
+                    data_points: vec![DataPoint {
+                        attributes: [KeyValue::new("queue", "jobs")][..].into(),
+                        start_time: None,
+                        time: Some(SystemTime::now()),
+                        value: 3_u64,
+                        exemplars: Vec::new(),
+                    }],
+                }),
+            }],
+        }],
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SlowRecordingClient {
+    requests: Arc<Mutex<Vec<Request<Vec<u8>>>>>,
+}
+
+impl SlowRecordingClient {
+    fn request_count(&self) -> usize {
+        self.requests
+            .lock()
+            .expect("requests mutex is healthy")
+            .len()
+    }
+}
+
+#[async_trait]
+impl HttpClient for SlowRecordingClient {
+    async fn send(&self, req: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        tokio::time::sleep(UPLOAD_DELAY).await;
+        self.requests
+            .lock()
+            .expect("requests mutex is healthy")
+            .push(req);
+        Ok(Response::builder()
+            .status(200)
+            .body(Bytes::from("{}"))
+            .expect("response is well formed"))
+    }
+}